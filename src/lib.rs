@@ -6,7 +6,7 @@ use core::{
   hint,
   mem::size_of,
   ptr::{self, NonNull, null_mut},
-  sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+  sync::atomic::{AtomicBool, AtomicPtr, AtomicU8, AtomicU32, AtomicU64, AtomicUsize, Ordering},
 };
 use std::{cell::UnsafeCell, sync::OnceLock};
 
@@ -14,21 +14,30 @@ use std::{cell::UnsafeCell, sync::OnceLock};
 // Constants
 // =============================================================================
 
-const ARENA_SIZE: usize = 1 << 30; // 1GB
+/// The arena grows in segments of this size, each with its own buddy allocator.
+const SEGMENT_SIZE_BITS: usize = 30;
+const SEGMENT_SIZE: usize = 1 << SEGMENT_SIZE_BITS; // 1GB
+/// Capacity of the segment table (`SEGMENT_MAP` stores segment numbers in a byte).
+const MAX_SEGMENTS: usize = 254;
+/// Default limit on the address space the arena reserves (`arena_size` option).
+const ARENA_SIZE: usize = 64 << 30; // 64GB
+/// Width of user-space addresses (4-level paging). Sizes the address-to-segment map.
+const ADDRESS_BITS: usize = 47;
 
 const SPAN_SIZE_BITS: usize = 16;
 const SPAN_SIZE: usize = 1 << SPAN_SIZE_BITS; // 64KB
-const SPAN_ALIGN_MASK: usize = !(SPAN_SIZE - 1);
+/// OS page size. The first page of a span (its header) is never decommitted.
+const PAGE_SIZE: usize = 4096;
 const SPAN_HEADER_SIZE: usize = size_of::<SpanHeader>();
 /// Owner ID for orphaned spans (no owning thread).
 const SPAN_OWNER_ORPHAN: u32 = 0;
 /// Magic number to identify valid SpanHeaders
 const SPAN_MAGIC: u64 = 0x494E_4943_5455_5321; // "INICTUS!"
 
-const SPANS_PER_ARENA: usize = ARENA_SIZE / SPAN_SIZE;
+const SPANS_PER_SEGMENT: usize = SEGMENT_SIZE / SPAN_SIZE;
 
-/// Largest allocation, that is equal to the whole arena.
-const BUDDY_MAX_ORDER: usize = SPANS_PER_ARENA.trailing_zeros() as usize;
+/// Largest allocation, that is equal to a whole segment.
+const BUDDY_MAX_ORDER: usize = SPANS_PER_SEGMENT.trailing_zeros() as usize;
 
 /// Number of linear size classes (16...128).
 const CLASSES_LINEAR: usize = 8;
@@ -36,27 +45,45 @@ const CLASSES_LINEAR_STEP: usize = 16;
 
 /// Classes per doubling in geometric progression
 const CLASSES_PER_DOUBLING: usize = 4;
+/// Largest small class: two blocks per 64KB span.
 const CLASSES_MAX_SIZE: usize = (SPAN_SIZE - SPAN_HEADER_SIZE) / 2;
 
-/// Returns the total number of size classes for small allocations. Computed at compile-time.
-const fn count_size_classes() -> usize {
+/// Medium classes are served from buddy blocks of up to `1 << MEDIUM_MAX_ORDER` spans (8MB).
+const MEDIUM_MAX_ORDER: usize = 7;
+/// Fewest blocks in a medium span, so less than 1/9 of it is left over.
+const MEDIUM_MIN_BLOCKS: usize = 8;
+/// Largest medium class. Larger allocations get a buddy block of their own.
+const MEDIUM_MAX_SIZE: usize =
+  ((SPAN_SIZE << MEDIUM_MAX_ORDER) - SPAN_HEADER_SIZE) / MEDIUM_MIN_BLOCKS;
+
+/// Returns the number of size classes up to `max_size`. Computed at compile-time.
+const fn count_size_classes(max_size: usize) -> usize {
   let mut class = 0;
   loop {
-    if class_to_size(class) >= CLASSES_MAX_SIZE {
+    if class_raw_size(class) >= max_size {
       return class + 1;
     }
     class += 1;
-    if class > 64 {
+    if class > 255 {
       return class;
     }
   }
 }
-/// Total number of size classes for small allocations.
-const CLASSES_COUNT: usize = count_size_classes();
+/// Number of small size classes (one 64KB span each).
+const SMALL_CLASSES_COUNT: usize = count_size_classes(CLASSES_MAX_SIZE);
+/// Total number of size classes, small and medium.
+const CLASSES_COUNT: usize = count_size_classes(MEDIUM_MAX_SIZE);
 
 /// Number of shards for global span caches (reuse + bounded).
 /// N shards matches typical CPU count (8 cores) for good cache locality.
 const SHARD_COUNT: usize = 8;
+/// Upper bound for the `shards` option.
+const MAX_SHARD_COUNT: usize = 1024;
+
+/// NUMA nodes with their own arena. Higher node ids share the last arena.
+const MAX_NUMA_NODES: usize = 16;
+/// CPUs covered by the CPU-to-node map (`cpu_id` returns 12 bits).
+const MAX_CPUS: usize = 4096;
 
 /// Cached spans per size class per thread. More = faster, but uses more memory.
 const THREAD_LOCAL_CACHE_SIZE: usize = 2;
@@ -64,9 +91,41 @@ const THREAD_LOCAL_CACHE_SIZE: usize = 2;
 /// Maximum spans per shard per class in the reuse cache.
 const REUSE_CACHE_LIMIT: usize = 4;
 
+/// Maximum fully free spans per shard per class kept warm in the global cache.
+/// Overflow goes to the class-agnostic empty pool. A medium class keeps at most as many bytes,
+/// but always one span: `(GLOBAL_CACHE_LIMIT >> order).max(1)`.
+const GLOBAL_CACHE_LIMIT: usize = 4;
+
 /// Maximum total active spans across all threads. Balance between throughput and RSS.
 const MAX_GLOBAL_ACTIVE_SPANS: usize = 4096; // 64KB * 4096 = 256MB
 
+/// Default time a fully free span may sit in a shared cache before it is purged to the buddy.
+const DEFAULT_DECAY_MS: u64 = 10_000;
+
+/// Minimum interval between two decay passes.
+const DECAY_INTERVAL_MS: u64 = 100;
+
+/// Work bound of a decay pass, which runs on an allocation slow path: spans returned per cache
+/// stack, and free buddy blocks scanned per order. Also the most blocks scanned per hold of a
+/// buddy order lock.
+const PURGE_BATCH: usize = 32;
+
+/// Default bound on freed huge mappings kept for reuse (`huge_cache` option).
+const HUGE_CACHE_LIMIT: usize = 256 << 20; // 256MB
+
+/// Freed buddy blocks of at least this order (1MB) are decommitted right away. Smaller ones
+/// stay committed for cheap reuse until they sit idle for the decay window.
+const DECOMMIT_MIN_ORDER: usize = 4;
+
+/// Transparent huge page size (x86-64 and aarch64 with 4KB base pages).
+const THP_REGION_SIZE: usize = 2 << 20; // 2MB
+/// Buddy order of a block covering a whole huge page region.
+const THP_REGION_ORDER: usize = (THP_REGION_SIZE / SPAN_SIZE).trailing_zeros() as usize;
+const THP_REGIONS_PER_SEGMENT: usize = SEGMENT_SIZE / THP_REGION_SIZE;
+
+/// Largest block size counted as a hot class by [`ThpPolicy::HotClasses`].
+const THP_HOT_MAX_SIZE: usize = 1024;
+
 /// Global counter of spans currently in use (not in buddy allocator).
 /// Incremented when span allocated from buddy, decremented when returned.
 static GLOBAL_ACTIVE_SPAN_COUNTER: AtomicUsize = AtomicUsize::new(0);
@@ -75,15 +134,27 @@ static GLOBAL_ACTIVE_SPAN_COUNTER: AtomicUsize = AtomicUsize::new(0);
 // Compile-Time Assertions
 // =============================================================================
 
-const _: () = assert!(ARENA_SIZE.is_power_of_two());
+const _: () = assert!(SEGMENT_SIZE.is_power_of_two());
 const _: () = assert!(SPAN_SIZE.is_power_of_two());
-const _: () = assert!(SPANS_PER_ARENA.is_power_of_two());
-const _: () = assert!(ARENA_SIZE % SPAN_SIZE == 0);
-const _: () = assert!(class_to_size(CLASSES_COUNT - 1) == CLASSES_MAX_SIZE);
+const _: () = assert!(SPANS_PER_SEGMENT.is_power_of_two());
+const _: () = assert!(SEGMENT_SIZE.is_multiple_of(SPAN_SIZE));
+const _: () = assert!(MAX_SEGMENTS < u8::MAX as usize);
+const _: () = assert!(MAX_NUMA_NODES <= u8::MAX as usize && MAX_NUMA_NODES <= 64);
+const _: () = assert!(ARENA_SIZE.is_multiple_of(SEGMENT_SIZE));
+const _: () = assert!(ARENA_SIZE / SEGMENT_SIZE <= MAX_SEGMENTS);
+const _: () = assert!(class_to_size(SMALL_CLASSES_COUNT - 1) == CLASSES_MAX_SIZE);
+const _: () = assert!(class_to_size(CLASSES_COUNT - 1) == MEDIUM_MAX_SIZE);
 const _: () = assert!(class_to_size(0) == 16);
 const _: () = assert!(CLASSES_MAX_SIZE >= 16);
-const _: () = assert!(SHARD_COUNT.is_power_of_two());
+const _: () = assert!(MEDIUM_MAX_SIZE.is_multiple_of(16));
+const _: () = assert!(class_order(SMALL_CLASSES_COUNT) > 0);
+const _: () = assert!(class_order(CLASSES_COUNT - 1) == MEDIUM_MAX_ORDER);
+const _: () = assert!(CLASSES_COUNT <= u8::MAX as usize);
+const _: () = assert!(SHARD_COUNT.is_power_of_two() && SHARD_COUNT <= MAX_SHARD_COUNT);
 const _: () = assert!(SPAN_HEADER_SIZE < SPAN_SIZE / 2);
+const _: () = assert!(SPAN_HEADER_SIZE <= PAGE_SIZE && PAGE_SIZE < SPAN_SIZE);
+const _: () = assert!(THP_REGION_SIZE.is_power_of_two() && THP_REGION_SIZE >= SPAN_SIZE);
+const _: () = assert!(SEGMENT_SIZE.is_multiple_of(THP_REGION_SIZE));
 const _: () = assert!(THREAD_LOCAL_CACHE_SIZE >= 1);
 const _: () = assert!(core::mem::offset_of!(SpanHeader, remote_free) >= 64);
 const _: () = assert!(SPAN_HEADER_SIZE == 128); // 2 cache lines
@@ -121,10 +192,19 @@ struct SpanHeader {
   block_size: u32,
   class: u8,
   kind: SpanKind,
-  /// Buddy order (0 = 1 span, 1 = 2 spans, ...).
+  /// Buddy order of a small span (0 = 1 span, 1 = 2 spans, ...).
   order: u8,
-  /// Padding to 64 bytes (39 bytes used, need 25 more).
-  _pad0: [u8; 25],
+  /// Payload pages may be resident. False for fresh or decommitted spans (zero pages read as false).
+  committed: bool,
+  /// Time (ms, monotonic) the span was last pushed to a shared cache. Drives decay purging.
+  last_used: u64,
+  /// Length of a Large run, in spans.
+  spans: u32,
+  /// Distance from a Large or huge header to its payload: `SPAN_HEADER_SIZE` unless the payload
+  /// is over-aligned.
+  offset: u32,
+  /// Padding to 64 bytes (56 bytes used, need 8 more).
+  _pad0: [u8; 8],
 
   // === Cache line 1: Cross-thread contended fields ===
   /// Free blocks from non-owner threads (lock-free Treiber stack).
@@ -173,6 +253,88 @@ unsafe fn os_munmap(ptr: *mut u8, size: usize) {
   unsafe { libc::munmap(ptr.cast(), size) };
 }
 
+/// Map `size` bytes starting `offset` bytes before a multiple of `align` (a power of two, above
+/// `offset`), unmapping the excess around them.
+unsafe fn os_mmap_aligned(size: usize, align: usize, offset: usize) -> *mut u8 {
+  let raw = unsafe { os_mmap(size + align) };
+  if raw.is_null() {
+    return null_mut();
+  }
+  let aligned = align_up(raw as usize + offset, align) - offset;
+  let (head, tail) = (aligned - raw as usize, align - (aligned - raw as usize));
+  unsafe {
+    if head != 0 {
+      os_munmap(raw, head);
+    }
+    if tail != 0 {
+      os_munmap((aligned + size) as *mut u8, tail);
+    }
+  }
+  aligned as *mut u8
+}
+
+/// Zero-initialized table mapped straight from the OS, for structures sized at runtime.
+/// Never unmapped. `T` must be valid when all-zero (atomics, `SpanStack`).
+struct OsArray<T> {
+  ptr: *mut T,
+  len: usize,
+}
+
+unsafe impl<T: Sync> Sync for OsArray<T> {}
+unsafe impl<T: Send> Send for OsArray<T> {}
+
+impl<T> OsArray<T> {
+  fn zeroed(len: usize) -> Self {
+    let ptr = unsafe { os_mmap(len * size_of::<T>()) } as *mut T;
+    if ptr.is_null() {
+      panic!("OsArray mmap failed");
+    }
+    Self { ptr, len }
+  }
+}
+
+impl<T> core::ops::Deref for OsArray<T> {
+  type Target = [T];
+
+  #[inline]
+  fn deref(&self) -> &[T] {
+    unsafe { core::slice::from_raw_parts(self.ptr, self.len) }
+  }
+}
+
+/// Release the physical pages backing `[ptr, ptr + size)`. The range stays mapped and is
+/// recommitted (zero-filled, or stale with `MADV_FREE`) on the next touch.
+#[cfg(feature = "release-mem")]
+unsafe fn os_decommit(ptr: *mut u8, size: usize) {
+  let advice = match RELEASE_MODE.load(Ordering::Relaxed) {
+    RELEASE_MODE_FREE => libc::MADV_FREE,
+    _ => libc::MADV_DONTNEED,
+  };
+  unsafe { libc::madvise(ptr.cast(), size, advice) };
+}
+
+/// Read up to `buf.len()` bytes of a small file (sysfs, procfs) without allocating.
+/// Returns the number of bytes read, 0 on error.
+fn os_read_file(path: &core::ffi::CStr, buf: &mut [u8]) -> usize {
+  let fd = unsafe { libc::open(path.as_ptr(), libc::O_RDONLY | libc::O_CLOEXEC) };
+  if fd < 0 {
+    return 0;
+  }
+  let n = unsafe { libc::read(fd, buf.as_mut_ptr().cast(), buf.len()) };
+  unsafe { libc::close(fd) };
+  n.max(0) as usize
+}
+
+/// Coarse monotonic clock in milliseconds (vDSO, no syscall).
+fn now_ms() -> u64 {
+  let mut ts = libc::timespec {
+    tv_sec: 0,
+    tv_nsec: 0,
+  };
+  unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC_COARSE, &mut ts) };
+  ts.tv_sec as u64 * 1000 + ts.tv_nsec as u64 / 1_000_000
+}
+
 // Each thread gets a different ID
 fn thread_id_u32() -> u32 {
   thread_local! {
@@ -207,54 +369,603 @@ fn cpu_id() -> usize {
   (thread_id_u32() as usize) & 7
 }
 
+// =============================================================================
+// Memory Release
+// =============================================================================
+
+/// How the payload of free spans is returned to the OS (`release-mem` feature).
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ReleaseMode {
+  /// `MADV_DONTNEED`: pages are dropped immediately, RSS goes down right away.
+  DontNeed,
+  /// `MADV_FREE`: pages are reclaimed lazily under memory pressure. Cheaper, but RSS lags.
+  Free,
+}
+
+const RELEASE_MODE_DONTNEED: u8 = 0;
+const RELEASE_MODE_FREE: u8 = 1;
+
+static RELEASE_MODE: AtomicU8 = AtomicU8::new(RELEASE_MODE_DONTNEED);
+
+/// Select the `madvise` advice used to release free spans. Defaults to [`ReleaseMode::DontNeed`].
+pub fn set_release_mode(mode: ReleaseMode) {
+  let raw = match mode {
+    ReleaseMode::DontNeed => RELEASE_MODE_DONTNEED,
+    ReleaseMode::Free => RELEASE_MODE_FREE,
+  };
+  RELEASE_MODE.store(raw, Ordering::Relaxed);
+}
+
+/// Decay window in ms. `u64::MAX` disables decay purging.
+static DECAY_MS: AtomicU64 = AtomicU64::new(DEFAULT_DECAY_MS);
+
+/// Timestamp of the last decay pass (rate limiter, also elects the purging thread).
+static LAST_DECAY: AtomicU64 = AtomicU64::new(0);
+
+/// Set how long (ms) a fully free span may sit idle in a shared cache before it is returned to
+/// the buddy allocator and its pages released. `0` purges on the next slow path, `u64::MAX`
+/// keeps cached spans forever. Defaults to 10s.
+pub fn set_decay_ms(ms: u64) {
+  DECAY_MS.store(ms, Ordering::Relaxed);
+}
+
+/// Run a decay pass over every arena if the decay window allows it and no other thread is
+/// purging. Called from allocation slow paths, so idle memory is released without a dedicated
+/// thread.
+fn decay() {
+  let decay_ms = DECAY_MS.load(Ordering::Relaxed);
+  if decay_ms == u64::MAX {
+    return;
+  }
+
+  let now = now_ms();
+  let last = LAST_DECAY.load(Ordering::Relaxed);
+  if now.wrapping_sub(last) < decay_ms.min(DECAY_INTERVAL_MS) {
+    return;
+  }
+  if LAST_DECAY
+    .compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed)
+    .is_err()
+  {
+    return;
+  }
+
+  for arena in arenas() {
+    arena.purge(now.saturating_sub(decay_ms), PURGE_BATCH);
+  }
+  huge_cache_purge(now.saturating_sub(decay_ms));
+}
+
+/// Bumped to ask every thread to flush its local span cache on its next slow path.
+static PURGE_EPOCH: AtomicU32 = AtomicU32::new(0);
+
+/// Decommit the payload of a free block of `1 << order` spans, keeping the header page resident.
+/// No-op if the block is already decommitted, so re-releasing a span costs no syscall.
+#[inline]
+unsafe fn decommit_span(span: *mut SpanHeader, order: usize) {
+  #[cfg(feature = "release-mem")]
+  unsafe {
+    if (*span).committed {
+      os_decommit(
+        (span as *mut u8).add(PAGE_SIZE),
+        (SPAN_SIZE << order) - PAGE_SIZE,
+      );
+      (*span).committed = false;
+    }
+  }
+  #[cfg(not(feature = "release-mem"))]
+  let _ = (span, order);
+}
+
+// =============================================================================
+// Transparent Huge Pages
+// =============================================================================
+
+/// How the arena is advised for transparent huge pages.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ThpPolicy {
+  /// No advice: the system setting (`/sys/kernel/mm/transparent_hugepage/enabled`) decides.
+  System,
+  /// `MADV_NOHUGEPAGE` on the whole arena.
+  Off,
+  /// `MADV_HUGEPAGE` on the 2MB regions serving hot (small) size classes, `MADV_NOHUGEPAGE` on
+  /// the rest. Free spans inside an advised region keep their pages until the whole region is
+  /// free, so purging never splits a huge page that is still in use.
+  HotClasses,
+}
+
+const THP_POLICY_SYSTEM: u8 = 0;
+const THP_POLICY_OFF: u8 = 1;
+const THP_POLICY_HOT: u8 = 2;
+
+static THP_POLICY: AtomicU8 = AtomicU8::new(THP_POLICY_SYSTEM);
+
+/// Select the huge page policy of the arena. Defaults to [`ThpPolicy::System`].
+///
+/// Takes effect immediately: `Off` and `HotClasses` re-advise the whole arena `MADV_NOHUGEPAGE`
+/// (huge pages already in place stay until they are released) and hot regions are advised again
+/// as spans are handed out. Advice cannot be withdrawn, so going back to `System` once the arena
+/// carries advice fails and returns `false`.
+pub fn set_thp_policy(policy: ThpPolicy) -> bool {
+  let raw = match policy {
+    ThpPolicy::System => THP_POLICY_SYSTEM,
+    ThpPolicy::Off => THP_POLICY_OFF,
+    ThpPolicy::HotClasses => THP_POLICY_HOT,
+  };
+  let prev = THP_POLICY.swap(raw, Ordering::Relaxed);
+  if raw == THP_POLICY_SYSTEM {
+    if prev != THP_POLICY_SYSTEM && segments().next().is_some() {
+      THP_POLICY.store(prev, Ordering::Relaxed);
+      return false;
+    }
+    return true;
+  }
+  for segment in segments() {
+    for word in &segment.thp_regions {
+      word.store(0, Ordering::Relaxed);
+    }
+    let base = segment.base.load(Ordering::Acquire);
+    unsafe { libc::madvise(base.cast(), SEGMENT_SIZE, libc::MADV_NOHUGEPAGE) };
+  }
+  true
+}
+
+/// Number of 2MB arena regions currently backed by a huge page, from the `AnonHugePages` lines
+/// of `/proc/self/smaps`. Reads the file through a stack buffer (no allocation), but walks every
+/// mapping of the process: call it for diagnostics, not on a hot path.
+pub fn thp_backed_regions() -> usize {
+  if segments().next().is_none() {
+    return 0;
+  }
+
+  let fd = unsafe {
+    libc::open(
+      c"/proc/self/smaps".as_ptr(),
+      libc::O_RDONLY | libc::O_CLOEXEC,
+    )
+  };
+  if fd < 0 {
+    return 0;
+  }
+
+  let mut buf = [0u8; 4096];
+  // Only line prefixes matter (range, field name, value); longer lines are truncated.
+  let mut line = [0u8; 128];
+  let mut len = 0;
+  let mut inside = false;
+  let mut huge_kb = 0;
+  loop {
+    let n = unsafe { libc::read(fd, buf.as_mut_ptr().cast(), buf.len()) };
+    if n <= 0 {
+      break;
+    }
+    for &b in &buf[..n as usize] {
+      if b != b'\n' {
+        if len < line.len() {
+          line[len] = b;
+          len += 1;
+        }
+        continue;
+      }
+      let l = &line[..len];
+      len = 0;
+      if l
+        .first()
+        .is_some_and(|c| c.is_ascii_digit() || (b'a'..=b'f').contains(c))
+      {
+        // Mapping header: `start-end perms offset dev inode path`
+        let start = parse_hex(l);
+        let end = l
+          .iter()
+          .position(|&c| c == b'-')
+          .map_or(0, |dash| parse_hex(&l[dash + 1..]));
+        inside = segments().any(|segment| {
+          let lo = segment.base.load(Ordering::Relaxed) as usize;
+          start < lo + SEGMENT_SIZE && end > lo
+        });
+      } else if inside && let Some(value) = l.strip_prefix(b"AnonHugePages:") {
+        let value = value.trim_ascii_start();
+        huge_kb += parse_u64(value) as usize;
+      }
+    }
+  }
+  unsafe { libc::close(fd) };
+
+  huge_kb * 1024 / THP_REGION_SIZE
+}
+
+// =============================================================================
+// Memory Budget
+// =============================================================================
+
+/// Bytes currently mapped for huge allocations (outside the arena).
+static HUGE_MAPPED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+/// Huge mappings currently mapped, including those in the huge cache.
+static HUGE_MAPPINGS: AtomicUsize = AtomicUsize::new(0);
+
+/// Footprint above which every cache is purged on growth. `usize::MAX` = no limit.
+static SOFT_LIMIT: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// Footprint allocations may not grow past. `usize::MAX` = no limit.
+static HARD_LIMIT: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// Registered `LimitHandler`, as a raw fn pointer (null = none).
+static LIMIT_HANDLER: AtomicPtr<()> = AtomicPtr::new(null_mut());
+
+/// Timestamp of the last soft-limit purge. While live data stays above the soft limit, growth
+/// purges at most once per `DECAY_INTERVAL_MS` instead of on every allocation.
+static LAST_SOFT_PURGE: AtomicU64 = AtomicU64::new(0);
+
+/// Times one allocation consults the [`LimitHandler`] before failing.
+const LIMIT_HANDLER_RETRIES: usize = 8;
+
+/// Called when an allocation of `requested` bytes would push the footprint past the hard
+/// limit. Return true to retry (e.g. after freeing memory), false to fail the allocation.
+/// A retry that finds the footprint no lower fails too, as does the
+/// `LIMIT_HANDLER_RETRIES`th one. Must not allocate.
+pub type LimitHandler = fn(requested: usize, footprint: usize) -> bool;
+
+/// Memory owned by inictus: spans handed out by the buddy allocator (small, cached and large)
+/// plus huge mappings.
+pub fn memory_footprint() -> usize {
+  GLOBAL_ACTIVE_SPAN_COUNTER.load(Ordering::Relaxed) * SPAN_SIZE
+    + HUGE_MAPPED_BYTES.load(Ordering::Relaxed)
+}
+
+/// Set the soft limit in bytes. Growing past it purges every shared cache back to the buddy
+/// allocator first, at most once per decay interval. `usize::MAX` disables it.
+pub fn set_soft_limit(bytes: usize) {
+  SOFT_LIMIT.store(bytes, Ordering::Relaxed);
+}
+
+/// Set the hard limit in bytes. Allocations that would grow the footprint past it fail (or
+/// consult the [`LimitHandler`]). The check is not atomic with the growth, so concurrent
+/// allocations may overshoot it slightly. `usize::MAX` disables it.
+pub fn set_hard_limit(bytes: usize) {
+  HARD_LIMIT.store(bytes, Ordering::Relaxed);
+}
+
+/// Register (or clear) the handler consulted when the hard limit is hit.
+pub fn set_limit_handler(handler: Option<LimitHandler>) {
+  let raw = handler.map_or(null_mut(), |h| h as *mut ());
+  LIMIT_HANDLER.store(raw, Ordering::Release);
+}
+
+/// Admit `bytes` of new memory (buddy growth or a huge mapping) against the budget.
+#[inline]
+fn budget_admit(bytes: usize) -> bool {
+  let soft = SOFT_LIMIT.load(Ordering::Relaxed);
+  let hard = HARD_LIMIT.load(Ordering::Relaxed);
+  if soft == usize::MAX && hard == usize::MAX {
+    return true;
+  }
+  budget_admit_slow(bytes, soft, hard)
+}
+
+#[cold]
+fn budget_admit_slow(bytes: usize, soft: usize, hard: usize) -> bool {
+  let mut footprint = memory_footprint();
+  if footprint.saturating_add(bytes) > soft {
+    let now = now_ms();
+    let last = LAST_SOFT_PURGE.load(Ordering::Relaxed);
+    if now.wrapping_sub(last) >= DECAY_INTERVAL_MS
+      && LAST_SOFT_PURGE
+        .compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed)
+        .is_ok()
+    {
+      for arena in arenas() {
+        arena.purge(u64::MAX, usize::MAX);
+      }
+      huge_cache_purge(u64::MAX);
+      footprint = memory_footprint();
+    }
+  }
+
+  let mut retries = 0;
+  while footprint.saturating_add(bytes) > hard {
+    let raw = LIMIT_HANDLER.load(Ordering::Acquire);
+    if raw.is_null() || retries == LIMIT_HANDLER_RETRIES {
+      return false;
+    }
+    let handler: LimitHandler = unsafe { core::mem::transmute::<*mut (), LimitHandler>(raw) };
+    if !handler(bytes, footprint) {
+      return false;
+    }
+    let before = footprint;
+    footprint = memory_footprint();
+    if footprint >= before {
+      return false;
+    }
+    retries += 1;
+  }
+  true
+}
+
+// =============================================================================
+// Options
+// =============================================================================
+
+/// Spans each thread keeps per class (`tcache` option).
+static TCACHE_SIZE: AtomicUsize = AtomicUsize::new(THREAD_LOCAL_CACHE_SIZE);
+
+/// Spans per shard per class in the reuse cache (`reuse_limit` option).
+static REUSE_LIMIT: AtomicUsize = AtomicUsize::new(REUSE_CACHE_LIMIT);
+
+/// Active spans above which retired spans bypass the local and reuse caches
+/// (`max_active_spans` option).
+static MAX_ACTIVE_SPANS: AtomicUsize = AtomicUsize::new(MAX_GLOBAL_ACTIVE_SPANS);
+
+/// Largest allocation served from a Large run; anything bigger gets a huge mapping. Half a
+/// segment unless lowered by `mallopt(M_MMAP_THRESHOLD)`, never below `MEDIUM_MAX_SIZE`.
+static HUGE_THRESHOLD: AtomicUsize = AtomicUsize::new(SEGMENT_SIZE / 2);
+
+/// Settings fixed on first use: cache sharding and NUMA topology.
+struct Config {
+  /// Shards of each shared cache (power of two).
+  shards: usize,
+  /// Nodes with their own arena (1 = NUMA-unaware).
+  nodes: usize,
+  /// Topology comes from the `numa_nodes` option: segments are not bound to nodes.
+  fake_numa: bool,
+  /// Node of each CPU.
+  cpu_node: [u8; MAX_CPUS],
+}
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+#[inline]
+fn config() -> &'static Config {
+  CONFIG.get_or_init(load_options)
+}
+
+/// Apply `INICTUS_OPTIONS` (comma-separated `key=value`) and discover the NUMA topology. Sizes
+/// take an optional `K`/`M`/`G`/`T` suffix. Unknown keys and invalid values are ignored, so
+/// the compile-time defaults stay in effect. Never allocates.
+///
+/// - `arena_size`: address space the arena may reserve, rounded up to 1GB segments.
+/// - `shards`: shards of the shared span caches, rounded up to a power of two.
+/// - `tcache`: spans each thread caches per size class.
+/// - `reuse_limit`: spans per shard per class in the reuse cache.
+/// - `max_active_spans`: active spans above which retired spans skip the local caches.
+/// - `huge_cache`: bytes of freed huge mappings kept for reuse (0 disables the cache).
+/// - `numa_nodes`: fake topology of N nodes with CPUs assigned round-robin (1 disables NUMA).
+fn load_options() -> Config {
+  let raw = unsafe { libc::getenv(c"INICTUS_OPTIONS".as_ptr()) };
+  if raw.is_null() {
+    parse_options(&[])
+  } else {
+    parse_options(unsafe { core::ffi::CStr::from_ptr(raw) }.to_bytes())
+  }
+}
+
+/// Apply an option string in the `INICTUS_OPTIONS` format. See [`load_options`].
+fn parse_options(options: &[u8]) -> Config {
+  let mut config = Config {
+    shards: SHARD_COUNT,
+    nodes: 1,
+    fake_numa: false,
+    cpu_node: [0; MAX_CPUS],
+  };
+  let mut fake_nodes = 0;
+
+  for option in options.split(|&b| b == b',') {
+    let Some(eq) = option.iter().position(|&b| b == b'=') else {
+      continue;
+    };
+    let (key, value) = (option[..eq].trim_ascii(), option[eq + 1..].trim_ascii());
+    let Some(value) = parse_size(value) else {
+      continue;
+    };
+    match key {
+      b"arena_size" if value > 0 => {
+        let segments = value.div_ceil(SEGMENT_SIZE).min(MAX_SEGMENTS);
+        SEGMENT_LIMIT.store(segments, Ordering::Relaxed);
+      }
+      b"shards" if value > 0 => {
+        config.shards = value.min(MAX_SHARD_COUNT).next_power_of_two();
+      }
+      b"numa_nodes" if value > 0 => fake_nodes = value.min(MAX_NUMA_NODES),
+      b"tcache" => TCACHE_SIZE.store(value, Ordering::Relaxed),
+      b"reuse_limit" => REUSE_LIMIT.store(value, Ordering::Relaxed),
+      b"max_active_spans" => MAX_ACTIVE_SPANS.store(value, Ordering::Relaxed),
+      b"huge_cache" => HUGE_CACHE_MAX.store(value, Ordering::Relaxed),
+      _ => {}
+    }
+  }
+
+  if fake_nodes > 0 {
+    config.nodes = fake_nodes;
+    config.fake_numa = true;
+    for (cpu, node) in config.cpu_node.iter_mut().enumerate() {
+      *node = (cpu % fake_nodes) as u8;
+    }
+  } else {
+    load_topology(&mut config);
+  }
+  config
+}
+
+/// Parses a decimal size with an optional binary `K`/`M`/`G`/`T` suffix.
+fn parse_size(value: &[u8]) -> Option<usize> {
+  let digits = value.iter().take_while(|b| b.is_ascii_digit()).count();
+  if digits == 0 || digits > 19 {
+    return None;
+  }
+  let shift = match &value[digits..] {
+    b"" => 0,
+    b"k" | b"K" => 10,
+    b"m" | b"M" => 20,
+    b"g" | b"G" => 30,
+    b"t" | b"T" => 40,
+    _ => return None,
+  };
+  usize::try_from(parse_u64(&value[..digits]))
+    .ok()?
+    .checked_mul(1 << shift)
+}
+
+// =============================================================================
+// NUMA
+// =============================================================================
+
+/// Fill the CPU-to-node map from `/sys/devices/system/node`. Leaves a single node if the
+/// machine has one or sysfs is unavailable.
+fn load_topology(config: &mut Config) {
+  let mut buf = [0u8; 4096];
+  let len = os_read_file(c"/sys/devices/system/node/online", &mut buf);
+  let mut nodes = 0;
+  parse_cpu_list(&buf[..len], |node| nodes = nodes.max(node + 1));
+  if nodes <= 1 {
+    return;
+  }
+  config.nodes = nodes.min(MAX_NUMA_NODES);
+
+  const PREFIX: &[u8] = b"/sys/devices/system/node/node";
+  const SUFFIX: &[u8] = b"/cpulist\0";
+  let mut path = [0u8; 64];
+  path[..PREFIX.len()].copy_from_slice(PREFIX);
+  for node in 0..nodes {
+    let mut end = PREFIX.len() + write_decimal(&mut path[PREFIX.len()..], node);
+    path[end..end + SUFFIX.len()].copy_from_slice(SUFFIX);
+    end += SUFFIX.len();
+
+    let Ok(path) = core::ffi::CStr::from_bytes_with_nul(&path[..end]) else {
+      continue;
+    };
+    let len = os_read_file(path, &mut buf);
+    let arena = node.min(MAX_NUMA_NODES - 1) as u8;
+    parse_cpu_list(&buf[..len], |cpu| {
+      if cpu < MAX_CPUS {
+        config.cpu_node[cpu] = arena;
+      }
+    });
+  }
+}
+
+/// Calls `f` for every number of a sysfs list such as `0-7,16-23`.
+fn parse_cpu_list(list: &[u8], mut f: impl FnMut(usize)) {
+  for range in list.trim_ascii().split(|&b| b == b',') {
+    if range.is_empty() || !range[0].is_ascii_digit() {
+      continue;
+    }
+    let first = parse_u64(range) as usize;
+    let last = range
+      .iter()
+      .position(|&b| b == b'-')
+      .map_or(first, |dash| parse_u64(&range[dash + 1..]) as usize);
+    for n in first..=last.min(first + MAX_CPUS) {
+      f(n);
+    }
+  }
+}
+
+/// Prefer `node` for the pages of a fresh segment. Preferred rather than strict, so a full node
+/// spills over to the others instead of failing page faults.
+#[cfg(target_os = "linux")]
+unsafe fn os_bind_node(ptr: *mut u8, size: usize, node: usize) {
+  const MPOL_PREFERRED: libc::c_int = 1;
+  let mask: libc::c_ulong = 1 << node;
+  unsafe {
+    libc::syscall(
+      libc::SYS_mbind,
+      ptr,
+      size,
+      MPOL_PREFERRED,
+      &mask,
+      libc::c_ulong::BITS as libc::c_ulong,
+      0 as libc::c_uint,
+    )
+  };
+}
+
+#[cfg(not(target_os = "linux"))]
+unsafe fn os_bind_node(_ptr: *mut u8, _size: usize, _node: usize) {}
+
 // =============================================================================
 // Thread Heap
 // =============================================================================
 
 struct ThreadHeap {
   spans: [*mut SpanHeader; CLASSES_COUNT],
-  cache: [[*mut SpanHeader; THREAD_LOCAL_CACHE_SIZE]; CLASSES_COUNT],
+  /// Retired fully free spans per class, linked through `cache_next` (see `cache_push`).
+  cache: [*mut SpanHeader; CLASSES_COUNT],
   cache_len: [usize; CLASSES_COUNT],
   tid: u32,
   cpu: usize,
+  /// NUMA node of `cpu` when the heap was created; selects the arena.
+  node: usize,
+  /// Last `PURGE_EPOCH` this thread acted on.
+  purge_epoch: u32,
 }
 
 impl ThreadHeap {
   fn new() -> Self {
+    let cpu = cpu_id();
     Self {
       spans: [null_mut(); CLASSES_COUNT],
-      cache: [[null_mut(); THREAD_LOCAL_CACHE_SIZE]; CLASSES_COUNT],
+      cache: [null_mut(); CLASSES_COUNT],
       cache_len: [0; CLASSES_COUNT],
       tid: thread_id_u32(),
-      cpu: cpu_id(),
+      cpu,
+      node: config().cpu_node[cpu & (MAX_CPUS - 1)] as usize,
+      purge_epoch: PURGE_EPOCH.load(Ordering::Relaxed),
+    }
+  }
+
+  /// Flush the local span cache if a purge was requested since the last check.
+  #[inline]
+  fn check_purge(&mut self, arena: &Arena) {
+    let epoch = PURGE_EPOCH.load(Ordering::Relaxed);
+    if self.purge_epoch != epoch {
+      self.purge_epoch = epoch;
+      self.flush_cache(arena);
     }
   }
 
   fn cache_pop(&mut self, class: usize) -> *mut SpanHeader {
-    let len = self.cache_len[class];
-    if len > 0 {
-      self.cache_len[class] = len - 1;
-      self.cache[class][len - 1]
-    } else {
-      null_mut()
+    let span = self.cache[class];
+    if !span.is_null() {
+      self.cache[class] = unsafe { (*span).cache_next };
+      self.cache_len[class] -= 1;
+    }
+    span
+  }
+
+  /// Return every locally cached span to the buddy allocator. Returns the number of bytes.
+  fn flush_cache(&mut self, arena: &Arena) -> usize {
+    let mut flushed = 0;
+    for class in 0..CLASSES_COUNT {
+      let order = class_order(class);
+      loop {
+        let span = self.cache_pop(class);
+        if span.is_null() {
+          break;
+        }
+        arena.buddy_free(arena.span_to_idx(span), order, true);
+        flushed += SPAN_SIZE << order;
+      }
     }
+    flushed
   }
 
   fn cache_push(&mut self, class: usize, span: *mut SpanHeader) -> bool {
-    let len = self.cache_len[class];
-    if len < THREAD_LOCAL_CACHE_SIZE {
-      self.cache[class][len] = span;
-      self.cache_len[class] = len + 1;
-      true
-    } else {
-      false
+    // Like the global cache, a medium class keeps at most as many bytes as a small one, but
+    // one span unless caching is off.
+    let size = TCACHE_SIZE.load(Ordering::Relaxed);
+    if self.cache_len[class] >= (size >> class_order(class)).max(size.min(1)) {
+      return false;
     }
+    unsafe { (*span).cache_next = self.cache[class] };
+    self.cache[class] = span;
+    self.cache_len[class] += 1;
+    true
   }
 }
 
 impl Drop for ThreadHeap {
   fn drop(&mut self) {
-    let Some(arena) = ARENA.get() else {
+    let Some(arena) = ARENAS[self.node].get() else {
       return;
     };
 
@@ -266,21 +977,22 @@ impl Drop for ThreadHeap {
       }
 
       // Flush local cache to global.
-      for i in 0..self.cache_len[class] {
-        let cached_span = self.cache[class][i];
-        if !cached_span.is_null() {
-          unsafe {
-            // Ensure spans in cache are orphaned/free and not in reuse.
-            (*cached_span)
-              .owner
-              .store(SPAN_OWNER_ORPHAN, Ordering::Release);
-            (*cached_span).in_reuse.store(false, Ordering::Release);
-            (*cached_span)
-              .remote_free
-              .store(null_mut(), Ordering::Relaxed);
-          }
-          arena.global_push(self.cpu, class, cached_span);
+      loop {
+        let cached_span = self.cache_pop(class);
+        if cached_span.is_null() {
+          break;
+        }
+        unsafe {
+          // Ensure spans in cache are orphaned/free and not in reuse.
+          (*cached_span)
+            .owner
+            .store(SPAN_OWNER_ORPHAN, Ordering::Release);
+          (*cached_span).in_reuse.store(false, Ordering::Release);
+          (*cached_span)
+            .remote_free
+            .store(null_mut(), Ordering::Relaxed);
         }
+        arena.global_push(self.cpu, class, cached_span);
       }
     }
   }
@@ -324,6 +1036,11 @@ impl SpinLock {
 struct FreeList {
   head: *mut SpanHeader,
   count: usize,
+  /// Blocks whose payload is committed (`SpanHeader::committed`).
+  committed: usize,
+  /// Block after which `release_committed` resumes its scan (null = from the head). Reset
+  /// whenever that block leaves the list.
+  cursor: *mut SpanHeader,
 }
 
 impl FreeList {
@@ -331,6 +1048,18 @@ impl FreeList {
     Self {
       head: null_mut(),
       count: 0,
+      committed: 0,
+      cursor: null_mut(),
+    }
+  }
+
+  /// Account for `span` leaving the list.
+  #[inline]
+  fn unlinked(&mut self, span: *mut SpanHeader) {
+    self.count -= 1;
+    self.committed -= unsafe { (*span).committed } as usize;
+    if self.cursor == span {
+      self.cursor = null_mut();
     }
   }
 }
@@ -350,33 +1079,31 @@ impl LockedFreeList {
   }
 }
 
+/// Buddy allocator of one segment. Blocks are named by their global span index.
 struct Buddy {
   /// Free lists per order, each with its own lock.
   orders: [LockedFreeList; BUDDY_MAX_ORDER + 1],
+  /// Free spans across all orders. Lets a full segment be skipped without taking its locks.
+  free_spans: AtomicUsize,
 }
 
 unsafe impl Sync for Buddy {}
 
 impl Buddy {
   const fn new() -> Self {
-    const LOCKED_LIST: LockedFreeList = LockedFreeList::new();
     Self {
-      orders: [LOCKED_LIST; BUDDY_MAX_ORDER + 1],
+      orders: [const { LockedFreeList::new() }; BUDDY_MAX_ORDER + 1],
+      free_spans: AtomicUsize::new(0),
     }
   }
 
-  // Initialize `Buddy`. Should be called only once.
-  fn init(&self, base: *mut u8) {
-    // Push entire arena as one MAX_ORDER span
+  // Initialize `Buddy` with the segment starting at span `idx`. Should be called only once.
+  fn init(&self, arena: &Arena, idx: usize) {
+    // Push entire segment as one MAX_ORDER span
     self.orders[BUDDY_MAX_ORDER].lock.lock();
-    unsafe {
-      let list = &mut *self.orders[BUDDY_MAX_ORDER].list.get();
-      let span = base as *mut SpanHeader;
-      (*span).cache_next = null_mut();
-      list.head = span;
-      list.count = 1;
-    }
+    unsafe { self.push_locked(arena, idx, BUDDY_MAX_ORDER) };
     self.orders[BUDDY_MAX_ORDER].lock.unlock();
+    self.free_spans.store(SPANS_PER_SEGMENT, Ordering::Release);
   }
 
   /// Push span to free list at given order (caller must hold lock).
@@ -387,6 +1114,7 @@ impl Buddy {
     unsafe { (*span).cache_next = list.head };
     list.head = span;
     list.count += 1;
+    list.committed += unsafe { (*span).committed } as usize;
   }
 
   /// Pop span from free list at given order (caller must hold lock).
@@ -399,7 +1127,7 @@ impl Buddy {
       return None;
     }
     list.head = unsafe { (*span).cache_next };
-    list.count -= 1;
+    list.unlinked(span);
     Some(arena.span_to_idx(span))
   }
 
@@ -412,7 +1140,7 @@ impl Buddy {
 
     if list.head == buddy_span {
       list.head = unsafe { (*buddy_span).cache_next };
-      list.count -= 1;
+      list.unlinked(buddy_span);
       return true;
     }
 
@@ -422,7 +1150,7 @@ impl Buddy {
       let next = unsafe { (*prev).cache_next };
       if next == buddy_span {
         unsafe { (*prev).cache_next = (*buddy_span).cache_next };
-        list.count -= 1;
+        list.unlinked(buddy_span);
         return true;
       }
       prev = next;
@@ -431,13 +1159,19 @@ impl Buddy {
     false
   }
 
-  /// Allocate span of given order, splitting larger spans if needed.
+  /// Allocate span of given order, splitting larger spans if needed. Decommitted blocks are
+  /// recommitted lazily: their pages fault back in (zeroed) on first touch, no syscall.
   fn alloc(&self, arena: &Arena, order: usize) -> Option<usize> {
+    if self.free_spans.load(Ordering::Relaxed) < 1 << order {
+      return None;
+    }
+
     self.orders[order].lock.lock();
     let result = unsafe { self.pop_locked(arena, order) };
     self.orders[order].lock.unlock();
 
     if let Some(idx) = result {
+      self.free_spans.fetch_sub(1 << order, Ordering::Relaxed);
       GLOBAL_ACTIVE_SPAN_COUNTER.fetch_add(1 << order, Ordering::Relaxed);
       return Some(idx);
     }
@@ -448,12 +1182,16 @@ impl Buddy {
       self.orders[o].lock.unlock();
 
       if let Some(idx) = result {
+        // Split halves inherit the commit state of the block they were carved from.
+        let committed = unsafe { (*arena.idx_to_span(idx)).committed };
         for split in (order..o).rev() {
           let buddy_idx = idx + (1 << split);
+          unsafe { (*arena.idx_to_span(buddy_idx)).committed = committed };
           self.orders[split].lock.lock();
           unsafe { self.push_locked(arena, buddy_idx, split) };
           self.orders[split].lock.unlock();
         }
+        self.free_spans.fetch_sub(1 << order, Ordering::Relaxed);
         GLOBAL_ACTIVE_SPAN_COUNTER.fetch_add(1 << order, Ordering::Relaxed);
         return Some(idx);
       }
@@ -462,22 +1200,89 @@ impl Buddy {
     None
   }
 
-  /// Free span with buddy coalescing.
-  fn free(&self, arena: &Arena, mut idx: usize, mut order: usize) {
+  /// Extend the in-use run of `spans` spans at `idx` to `new_spans`: take the free blocks after
+  /// it up to the end of the aligned block covering `new_spans`, then give back the excess.
+  /// Each of those blocks has in-use spans in its lower buddy, so it is free at exactly its order
+  /// or not at all. Returns false, with nothing taken, if one is not free.
+  fn grow(&self, arena: &Arena, idx: usize, spans: usize, new_spans: usize) -> bool {
+    let end = idx + new_spans.next_power_of_two();
+    if idx & (new_spans.next_power_of_two() - 1) != 0
+      || self.free_spans.load(Ordering::Relaxed) < end - idx - spans
+    {
+      return false;
+    }
+
+    let mut taken = idx + spans;
+    for (piece, order) in buddy_pieces(idx + spans, end) {
+      self.orders[order].lock.lock();
+      let removed = unsafe { self.try_remove_buddy(arena, piece, order) };
+      self.orders[order].lock.unlock();
+      if !removed {
+        break;
+      }
+      taken = piece + (1 << order);
+    }
+
+    if taken < end {
+      // Put the blocks back. Their lower buddies are still in use: no coalescing.
+      for (piece, order) in buddy_pieces(idx + spans, taken) {
+        self.orders[order].lock.lock();
+        unsafe { self.push_locked(arena, piece, order) };
+        self.orders[order].lock.unlock();
+      }
+      return false;
+    }
+
+    self
+      .free_spans
+      .fetch_sub(end - idx - spans, Ordering::Relaxed);
+    GLOBAL_ACTIVE_SPAN_COUNTER.fetch_add(end - idx - spans, Ordering::Relaxed);
+
+    // The excess inherits the commit state of the blocks it is cut from.
+    for (piece, order) in buddy_pieces(idx + spans, end) {
+      let committed = unsafe { (*arena.idx_to_span(piece)).committed };
+      for (part, part_order) in buddy_pieces(piece.max(idx + new_spans), piece + (1 << order)) {
+        unsafe { (*arena.idx_to_span(part)).committed = committed };
+        self.free(arena, part, part_order, false);
+      }
+    }
+    true
+  }
+
+  /// Free span with buddy coalescing. The coalesced block is decommitted before it is published
+  /// if `release` is set or it reaches `DECOMMIT_MIN_ORDER`.
+  fn free(&self, arena: &Arena, idx: usize, order: usize, release: bool) {
     GLOBAL_ACTIVE_SPAN_COUNTER.fetch_sub(1 << order, Ordering::Relaxed);
+    self.insert(arena, idx, order, false, release);
+    self.free_spans.fetch_add(1 << order, Ordering::Relaxed);
+  }
 
-    // Coalesce with buddy, climbing orders
+  /// Insert a free block, coalescing with its buddies. `dirty` means some page of the block may
+  /// be resident even if its header says the payload is released. Blocks below
+  /// `DECOMMIT_MIN_ORDER` with a committed payload stay committed unless `release` is set.
+  fn insert(
+    &self,
+    arena: &Arena,
+    mut idx: usize,
+    mut order: usize,
+    mut dirty: bool,
+    release: bool,
+  ) {
+    let mut payload = unsafe { (*arena.idx_to_span(idx)).committed };
+    dirty |= payload;
+
+    // Coalesce with buddy, climbing orders (never past the segment)
     while order < BUDDY_MAX_ORDER {
       let buddy_idx = idx ^ (1 << order);
-      if buddy_idx >= SPANS_PER_ARENA {
-        break;
-      }
 
       self.orders[order].lock.lock();
       let removed = unsafe { self.try_remove_buddy(arena, buddy_idx, order) };
       self.orders[order].lock.unlock();
 
       if removed {
+        // The absorbed header page is resident even if its payload was released.
+        dirty = true;
+        payload |= unsafe { (*arena.idx_to_span(buddy_idx)).committed };
         idx = idx.min(buddy_idx);
         order += 1;
       } else {
@@ -485,39 +1290,230 @@ impl Buddy {
       }
     }
 
-    self.orders[order].lock.lock();
-    unsafe { self.push_locked(arena, idx, order) };
-    self.orders[order].lock.unlock();
-  }
-}
+    if dirty {
+      let span = arena.idx_to_span(idx);
+      unsafe {
+        (*span).committed = true;
+        let keep = payload && !release && order < DECOMMIT_MIN_ORDER;
+        // Releasing part of a huge page splits it: wait until the whole region is free.
+        let split_thp = order < THP_REGION_ORDER && arena.thp_advised(idx);
+        if keep || split_thp {
+          (*span).last_used = now_ms();
+        } else {
+          decommit_span(span, order);
+        }
+      }
+    }
+
+    self.orders[order].lock.lock();
+    unsafe { self.push_locked(arena, idx, order) };
+    self.orders[order].lock.unlock();
+  }
+
+  /// Merge free buddy pairs that racing frees left uncoalesced. Each free list is sorted by
+  /// address under its lock so buddies become neighbours. Returns the number of merges.
+  fn coalesce(&self, arena: &Arena) -> usize {
+    const BATCH: usize = 64;
+    let mut merged = 0;
+
+    for order in 0..BUDDY_MAX_ORDER {
+      loop {
+        let mut pairs = [0usize; BATCH];
+        let mut found = 0;
+
+        self.orders[order].lock.lock();
+        unsafe {
+          let list = &mut *self.orders[order].list.get();
+          list.head = sort_span_list(list.head);
+          list.cursor = null_mut();
+
+          let mut link: *mut *mut SpanHeader = &mut list.head;
+          while found < BATCH && !(*link).is_null() {
+            let cur = *link;
+            let next = (*cur).cache_next;
+            let cur_idx = arena.span_to_idx(cur);
+            if !next.is_null()
+              && cur_idx & (1 << order) == 0
+              && arena.span_to_idx(next) == cur_idx + (1 << order)
+            {
+              *link = (*next).cache_next;
+              list.unlinked(cur);
+              list.unlinked(next);
+              // The merged block carries the payload of both halves.
+              (*cur).committed |= (*next).committed;
+              pairs[found] = cur_idx;
+              found += 1;
+            } else {
+              link = &mut (*cur).cache_next;
+            }
+          }
+        }
+        self.orders[order].lock.unlock();
+
+        for &idx in &pairs[..found] {
+          self.insert(arena, idx, order + 1, true, false);
+        }
+        merged += found;
+        if found < BATCH {
+          break;
+        }
+      }
+    }
+
+    merged
+  }
+
+  /// Decommit free blocks left committed that were freed at `cutoff` (ms) or earlier, except
+  /// those holding part of an in-use huge page. Scans at most `limit` blocks per order, resuming
+  /// where the previous call stopped, and at most `PURGE_BATCH` per hold of an order lock.
+  /// Returns the number of bytes released.
+  fn release_committed(&self, arena: &Arena, cutoff: u64, limit: usize) -> usize {
+    if !cfg!(feature = "release-mem") {
+      return 0;
+    }
+
+    let mut released = 0;
+    for order in 0..=BUDDY_MAX_ORDER {
+      let mut scanned = 0;
+      let mut done = false;
+      while !done && scanned < limit {
+        // Unlink a batch of stale committed blocks, then release them outside the lock.
+        let mut taken: *mut SpanHeader = null_mut();
+        self.orders[order].lock.lock();
+        unsafe {
+          let list = &mut *self.orders[order].list.get();
+          let mut link: *mut *mut SpanHeader = if list.cursor.is_null() {
+            &mut list.head
+          } else {
+            &mut (*list.cursor).cache_next
+          };
+          let batch_end = scanned + PURGE_BATCH.min(limit - scanned);
+          while list.committed != 0 && scanned < batch_end && !(*link).is_null() {
+            let cur = *link;
+            let idx = arena.span_to_idx(cur);
+            scanned += 1;
+            if (*cur).committed
+              && (*cur).last_used <= cutoff
+              && (order >= THP_REGION_ORDER || !arena.thp_advised(idx))
+            {
+              *link = (*cur).cache_next;
+              list.unlinked(cur);
+              (*cur).cache_next = taken;
+              taken = cur;
+            } else {
+              list.cursor = cur;
+              link = &mut (*cur).cache_next;
+            }
+          }
+          done = list.committed == 0 || (*link).is_null();
+          if done {
+            list.cursor = null_mut();
+          }
+        }
+        self.orders[order].lock.unlock();
+
+        while !taken.is_null() {
+          let next = unsafe { (*taken).cache_next };
+          self.insert(arena, arena.span_to_idx(taken), order, false, true);
+          released += SPAN_SIZE << order;
+          taken = next;
+        }
+      }
+    }
+    released
+  }
+
+  /// Number of free blocks per order, and how many of them have a committed payload.
+  fn free_counts(&self) -> ([usize; BUDDY_MAX_ORDER + 1], [usize; BUDDY_MAX_ORDER + 1]) {
+    let mut counts = [0; BUDDY_MAX_ORDER + 1];
+    let mut committed = [0; BUDDY_MAX_ORDER + 1];
+    for order in 0..=BUDDY_MAX_ORDER {
+      self.orders[order].lock.lock();
+      let list = unsafe { &*self.orders[order].list.get() };
+      (counts[order], committed[order]) = (list.count, list.committed);
+      self.orders[order].lock.unlock();
+    }
+    (counts, committed)
+  }
+}
+
+/// Split the span range `[start, end)` into the aligned buddy blocks that tile it, taking the
+/// largest block that fits at each step. Yields `(idx, order)`.
+fn buddy_pieces(start: usize, end: usize) -> impl Iterator<Item = (usize, usize)> {
+  let mut idx = start;
+  core::iter::from_fn(move || {
+    (idx < end).then(|| {
+      let order = (idx.trailing_zeros() as usize)
+        .min((end - idx).ilog2() as usize)
+        .min(BUDDY_MAX_ORDER);
+      idx += 1 << order;
+      (idx - (1 << order), order)
+    })
+  })
+}
+
+/// Sort a `cache_next`-linked list of spans by address (in-place merge sort, no allocation).
+unsafe fn sort_span_list(head: *mut SpanHeader) -> *mut SpanHeader {
+  if head.is_null() || unsafe { (*head).cache_next }.is_null() {
+    return head;
+  }
+
+  // Split in halves (slow/fast pointers).
+  let mut slow = head;
+  let mut fast = unsafe { (*head).cache_next };
+  while !fast.is_null() && !unsafe { (*fast).cache_next }.is_null() {
+    slow = unsafe { (*slow).cache_next };
+    fast = unsafe { (*(*fast).cache_next).cache_next };
+  }
+  let second = unsafe { (*slow).cache_next };
+  unsafe { (*slow).cache_next = null_mut() };
+
+  let mut a = unsafe { sort_span_list(head) };
+  let mut b = unsafe { sort_span_list(second) };
+
+  // Merge.
+  let mut merged: *mut SpanHeader = null_mut();
+  let mut tail: *mut *mut SpanHeader = &mut merged;
+  while !a.is_null() && !b.is_null() {
+    let pick = if a < b { &mut a } else { &mut b };
+    unsafe {
+      *tail = *pick;
+      tail = &mut (**pick).cache_next;
+      *pick = (**pick).cache_next;
+    }
+  }
+  unsafe { *tail = if a.is_null() { b } else { a } };
+  merged
+}
 
 // =============================================================================
-// Global Cache (per-shard, per-class)
+// Span Stack (lock-free Treiber stack)
 // =============================================================================
 
-struct GlobalCache {
-  heads: [[AtomicU64; CLASSES_COUNT]; SHARD_COUNT],
+/// Lock-free stack of spans linked through `cache_next`. Spans are 64KB aligned, so the low
+/// 16 bits of the packed head hold an ABA tag bumped on every update. All-zero is the empty
+/// stack, so tables of stacks are mapped zeroed (`OsArray`).
+struct SpanStack {
+  head: AtomicU64,
 }
 
-impl GlobalCache {
-  const fn new() -> Self {
-    const ROW: [AtomicU64; CLASSES_COUNT] = [const { AtomicU64::new(0) }; CLASSES_COUNT];
-    Self {
-      heads: [ROW; SHARD_COUNT],
-    }
+impl SpanStack {
+  #[inline]
+  fn is_empty(&self) -> bool {
+    self.head.load(Ordering::Relaxed) & !0xFFFF == 0
   }
 
-  fn pop(&self, shard: usize, class: usize) -> *mut SpanHeader {
-    let head = &self.heads[shard & (SHARD_COUNT - 1)][class];
+  fn pop(&self) -> *mut SpanHeader {
     loop {
-      let packed_head = head.load(Ordering::Acquire);
+      let packed_head = self.head.load(Ordering::Acquire);
       let ptr = (packed_head & !0xFFFF) as *mut SpanHeader;
       if ptr.is_null() {
         return null_mut();
       }
       let next = unsafe { (*ptr).cache_next };
       let new_packed = (next as u64) | (((packed_head as u16).wrapping_add(1)) as u64);
-      if head
+      if self
+        .head
         .compare_exchange_weak(packed_head, new_packed, Ordering::AcqRel, Ordering::Relaxed)
         .is_ok()
       {
@@ -526,13 +1522,34 @@ impl GlobalCache {
     }
   }
 
-  fn push(&self, shard: usize, class: usize, span: *mut SpanHeader) {
-    let head = &self.heads[shard & (SHARD_COUNT - 1)][class];
+  fn push(&self, span: *mut SpanHeader) {
     loop {
-      let packed_head = head.load(Ordering::Relaxed);
+      let packed_head = self.head.load(Ordering::Relaxed);
       unsafe { (*span).cache_next = (packed_head & !0xFFFF) as *mut SpanHeader };
       let new_packed = (span as u64) | (((packed_head as u16).wrapping_add(1)) as u64);
-      if head
+      if self
+        .head
+        .compare_exchange_weak(
+          packed_head,
+          new_packed,
+          Ordering::Release,
+          Ordering::Relaxed,
+        )
+        .is_ok()
+      {
+        return;
+      }
+    }
+  }
+
+  /// Push the list `first..=last`, linked through `cache_next`, keeping its order.
+  fn push_list(&self, first: *mut SpanHeader, last: *mut SpanHeader) {
+    loop {
+      let packed_head = self.head.load(Ordering::Relaxed);
+      unsafe { (*last).cache_next = (packed_head & !0xFFFF) as *mut SpanHeader };
+      let new_packed = (first as u64) | (((packed_head as u16).wrapping_add(1)) as u64);
+      if self
+        .head
         .compare_exchange_weak(
           packed_head,
           new_packed,
@@ -545,6 +1562,130 @@ impl GlobalCache {
       }
     }
   }
+
+  /// Detach the whole stack. The returned list (linked through `cache_next`) is owned by the caller.
+  fn take_all(&self) -> *mut SpanHeader {
+    loop {
+      let packed_head = self.head.load(Ordering::Acquire);
+      let ptr = (packed_head & !0xFFFF) as *mut SpanHeader;
+      if ptr.is_null() {
+        return null_mut();
+      }
+      let new_packed = ((packed_head as u16).wrapping_add(1)) as u64;
+      if self
+        .head
+        .compare_exchange_weak(packed_head, new_packed, Ordering::AcqRel, Ordering::Relaxed)
+        .is_ok()
+      {
+        return ptr;
+      }
+    }
+  }
+}
+
+// =============================================================================
+// Global Cache (per-shard, per-class)
+// =============================================================================
+
+/// Fully free spans still formatted for their class. Bounded per shard and class; overflow
+/// goes to the class-agnostic `EmptyPool`.
+struct GlobalCache {
+  /// `shards * CLASSES_COUNT` stacks, shard-major.
+  heads: OsArray<SpanStack>,
+  counts: OsArray<AtomicUsize>,
+  shard_mask: usize,
+}
+
+impl GlobalCache {
+  fn new(shards: usize) -> Self {
+    Self {
+      heads: OsArray::zeroed(shards * CLASSES_COUNT),
+      counts: OsArray::zeroed(shards * CLASSES_COUNT),
+      shard_mask: shards - 1,
+    }
+  }
+
+  #[inline]
+  fn slot(&self, shard: usize, class: usize) -> usize {
+    (shard & self.shard_mask) * CLASSES_COUNT + class
+  }
+
+  fn pop(&self, shard: usize, class: usize) -> *mut SpanHeader {
+    let slot = self.slot(shard, class);
+    let span = self.heads[slot].pop();
+    if !span.is_null() {
+      self.counts[slot].fetch_sub(1, Ordering::Relaxed);
+    }
+    span
+  }
+
+  fn push(&self, shard: usize, class: usize, span: *mut SpanHeader) -> bool {
+    let slot = self.slot(shard, class);
+    let count = &self.counts[slot];
+
+    let limit = (GLOBAL_CACHE_LIMIT >> class_order(class)).max(1);
+    if count.load(Ordering::Relaxed) >= limit {
+      return false;
+    }
+
+    unsafe { (*span).last_used = now_ms() };
+    self.heads[slot].push(span);
+    count.fetch_add(1, Ordering::Relaxed);
+    true
+  }
+
+  fn len(&self) -> usize {
+    self.counts.iter().map(|c| c.load(Ordering::Relaxed)).sum()
+  }
+
+  fn bytes(&self) -> usize {
+    let counts = self.counts.iter().map(|c| c.load(Ordering::Relaxed));
+    counts
+      .enumerate()
+      .map(|(slot, count)| count * (SPAN_SIZE << class_order(slot % CLASSES_COUNT)))
+      .sum()
+  }
+}
+
+// =============================================================================
+// Empty Pool (class-agnostic fully free spans)
+// =============================================================================
+
+/// Fully free spans usable by any class (`init_span` reformats them anyway).
+struct EmptyPool {
+  heads: OsArray<SpanStack>,
+  counts: OsArray<AtomicUsize>,
+  shard_mask: usize,
+}
+
+impl EmptyPool {
+  fn new(shards: usize) -> Self {
+    Self {
+      heads: OsArray::zeroed(shards),
+      counts: OsArray::zeroed(shards),
+      shard_mask: shards - 1,
+    }
+  }
+
+  fn pop(&self, shard: usize) -> *mut SpanHeader {
+    let shard_idx = shard & self.shard_mask;
+    let span = self.heads[shard_idx].pop();
+    if !span.is_null() {
+      self.counts[shard_idx].fetch_sub(1, Ordering::Relaxed);
+    }
+    span
+  }
+
+  fn push(&self, shard: usize, span: *mut SpanHeader) {
+    let shard_idx = shard & self.shard_mask;
+    unsafe { (*span).last_used = now_ms() };
+    self.heads[shard_idx].push(span);
+    self.counts[shard_idx].fetch_add(1, Ordering::Relaxed);
+  }
+
+  fn len(&self) -> usize {
+    self.counts.iter().map(|c| c.load(Ordering::Relaxed)).sum()
+  }
 }
 
 // =============================================================================
@@ -552,133 +1693,318 @@ impl GlobalCache {
 // =============================================================================
 
 struct ReuseCache {
-  heads: [[AtomicU64; CLASSES_COUNT]; SHARD_COUNT],
-  counts: [[AtomicUsize; CLASSES_COUNT]; SHARD_COUNT],
+  /// `shards * CLASSES_COUNT` stacks, shard-major.
+  heads: OsArray<SpanStack>,
+  counts: OsArray<AtomicUsize>,
+  shard_mask: usize,
 }
 
 impl ReuseCache {
-  const fn new() -> Self {
-    const HEADS_ROW: [AtomicU64; CLASSES_COUNT] = [const { AtomicU64::new(0) }; CLASSES_COUNT];
-    const COUNTS_ROW: [AtomicUsize; CLASSES_COUNT] = [const { AtomicUsize::new(0) }; CLASSES_COUNT];
+  fn new(shards: usize) -> Self {
     Self {
-      heads: [HEADS_ROW; SHARD_COUNT],
-      counts: [COUNTS_ROW; SHARD_COUNT],
+      heads: OsArray::zeroed(shards * CLASSES_COUNT),
+      counts: OsArray::zeroed(shards * CLASSES_COUNT),
+      shard_mask: shards - 1,
     }
   }
 
+  #[inline]
+  fn slot(&self, shard: usize, class: usize) -> usize {
+    (shard & self.shard_mask) * CLASSES_COUNT + class
+  }
+
   fn pop(&self, shard: usize, class: usize) -> *mut SpanHeader {
-    let shard_idx = shard & (SHARD_COUNT - 1);
-    let head = &self.heads[shard_idx][class];
-    loop {
-      let packed_head = head.load(Ordering::Acquire);
-      let ptr = (packed_head & !0xFFFF) as *mut SpanHeader;
-      if ptr.is_null() {
-        return null_mut();
-      }
-      let next = unsafe { (*ptr).cache_next };
-      let new_packed = (next as u64) | (((packed_head as u16).wrapping_add(1)) as u64);
-      if head
-        .compare_exchange_weak(packed_head, new_packed, Ordering::AcqRel, Ordering::Relaxed)
-        .is_ok()
-      {
-        self.counts[shard_idx][class].fetch_sub(1, Ordering::Relaxed);
-        return ptr;
-      }
+    let slot = self.slot(shard, class);
+    let span = self.heads[slot].pop();
+    if !span.is_null() {
+      self.counts[slot].fetch_sub(1, Ordering::Relaxed);
     }
+    span
   }
 
   fn push(&self, shard: usize, class: usize, span: *mut SpanHeader) -> bool {
-    let shard_idx = shard & (SHARD_COUNT - 1);
-    let count = &self.counts[shard_idx][class];
+    let slot = self.slot(shard, class);
+    let count = &self.counts[slot];
 
-    if count.load(Ordering::Relaxed) >= REUSE_CACHE_LIMIT {
+    if count.load(Ordering::Relaxed) >= REUSE_LIMIT.load(Ordering::Relaxed) {
       return false;
     }
 
-    let head = &self.heads[shard_idx][class];
-    loop {
-      let packed_head = head.load(Ordering::Relaxed);
-      unsafe { (*span).cache_next = (packed_head & !0xFFFF) as *mut SpanHeader };
-      let new_packed = (span as u64) | (((packed_head as u16).wrapping_add(1)) as u64);
-      if head
-        .compare_exchange_weak(
-          packed_head,
-          new_packed,
-          Ordering::Release,
-          Ordering::Relaxed,
-        )
-        .is_ok()
-      {
-        count.fetch_add(1, Ordering::Relaxed);
-        return true;
-      }
+    unsafe { (*span).last_used = now_ms() };
+    self.heads[slot].push(span);
+    count.fetch_add(1, Ordering::Relaxed);
+    true
+  }
+
+  fn len(&self) -> usize {
+    self.counts.iter().map(|c| c.load(Ordering::Relaxed)).sum()
+  }
+}
+
+// =============================================================================
+// Page Map (lock-free radix tree from address to span header)
+// =============================================================================
+
+// One entry per 64KB span of everything inictus hands out: arena spans (every span of a
+// Large block points at its head) and huge mappings, which are span-aligned so no span is
+// shared. 7 + 12 + 12 bits cover the 47-bit address space.
+const PAGE_MAP_NODE_BITS: usize = 12;
+const PAGE_MAP_ROOT_BITS: usize = ADDRESS_BITS - SPAN_SIZE_BITS - 2 * PAGE_MAP_NODE_BITS;
+
+type PageMapLeaf = [AtomicPtr<SpanHeader>; 1 << PAGE_MAP_NODE_BITS];
+type PageMapNode = [AtomicPtr<PageMapLeaf>; 1 << PAGE_MAP_NODE_BITS];
+
+/// Interior nodes and leaves are mapped on first use and never freed.
+static PAGE_MAP: [AtomicPtr<PageMapNode>; 1 << PAGE_MAP_ROOT_BITS] =
+  [const { AtomicPtr::new(null_mut()) }; 1 << PAGE_MAP_ROOT_BITS];
+
+/// Child at `slot`, mapping and publishing a zeroed one if `create` is set and there is none.
+fn page_map_child<T>(slot: &AtomicPtr<T>, create: bool) -> Option<&'static T> {
+  let child = slot.load(Ordering::Acquire);
+  if !child.is_null() {
+    return Some(unsafe { &*child });
+  }
+  if !create {
+    return None;
+  }
+
+  let fresh = unsafe { os_mmap(size_of::<T>()) } as *mut T;
+  if fresh.is_null() {
+    return None;
+  }
+  match slot.compare_exchange(null_mut(), fresh, Ordering::AcqRel, Ordering::Acquire) {
+    Ok(_) => Some(unsafe { &*fresh }),
+    Err(winner) => {
+      unsafe { os_munmap(fresh.cast(), size_of::<T>()) };
+      Some(unsafe { &*winner })
+    }
+  }
+}
+
+/// Entry of the span containing `addr`. None if out of range or not mapped (and not `create`).
+#[inline]
+fn page_map_entry(addr: usize, create: bool) -> Option<&'static AtomicPtr<SpanHeader>> {
+  let key = addr >> SPAN_SIZE_BITS;
+  let mask = (1 << PAGE_MAP_NODE_BITS) - 1;
+  let root = PAGE_MAP.get(key >> (2 * PAGE_MAP_NODE_BITS))?;
+  let node = page_map_child(root, create)?;
+  let leaf = page_map_child(&node[(key >> PAGE_MAP_NODE_BITS) & mask], create)?;
+  Some(&leaf[key & mask])
+}
+
+/// Map the nodes covering `[start, start + len)`. Fails if the OS is out of memory.
+fn page_map_reserve(start: usize, len: usize) -> bool {
+  (start..start + len)
+    .step_by(SPAN_SIZE)
+    .all(|addr| page_map_entry(addr, true).is_some())
+}
+
+/// Point every span of `[start, start + len)` at `span`, or clear them with null. The range
+/// must be reserved.
+fn page_map_set(start: usize, len: usize, span: *mut SpanHeader) {
+  for addr in (start..start + len).step_by(SPAN_SIZE) {
+    if let Some(entry) = page_map_entry(addr, false) {
+      entry.store(span, Ordering::Release);
+    }
+  }
+}
+
+/// Distance from a Large or huge header to its payload.
+#[inline]
+fn payload_offset(span: *mut SpanHeader) -> usize {
+  unsafe { (*span).offset as usize }
+}
+
+/// Header of the block `ptr` was returned for: the start of a Large or huge block, or any
+/// address in the payload of a small span. Never dereferences `ptr`, so foreign and interior
+/// pointers just return None.
+#[inline]
+fn block_span(ptr: *mut u8) -> Option<*mut SpanHeader> {
+  let span = page_map_entry(ptr as usize, false)?.load(Ordering::Acquire);
+  if span.is_null() {
+    return None;
+  }
+  let valid = match unsafe { (*span).kind } {
+    SpanKind::Small => ptr as usize >= span as usize + SPAN_HEADER_SIZE,
+    SpanKind::Large | SpanKind::Huge => ptr as usize == span as usize + payload_offset(span),
+  };
+  valid.then_some(span)
+}
+
+// =============================================================================
+// Segments
+// =============================================================================
+
+/// A `SEGMENT_SIZE`-aligned reservation the arena carves spans from. Span indices are global:
+/// the segment number sits above the `BUDDY_MAX_ORDER` bits of the index within the segment.
+struct Segment {
+  /// Null until the segment is reserved.
+  base: AtomicPtr<u8>,
+  /// Node of the arena that owns the segment.
+  node: AtomicUsize,
+  buddy: Buddy,
+  /// One bit per span: orphaned partially used spans that `retire_small_span` left outside
+  /// every cache. Scanned by the background thread.
+  orphans: [AtomicU64; SPANS_PER_SEGMENT / 64],
+  /// One bit per 2MB region advised `MADV_HUGEPAGE` ([`ThpPolicy::HotClasses`]).
+  thp_regions: [AtomicU64; THP_REGIONS_PER_SEGMENT.div_ceil(64)],
+}
+
+impl Segment {
+  const fn new() -> Self {
+    Self {
+      base: AtomicPtr::new(null_mut()),
+      node: AtomicUsize::new(0),
+      buddy: Buddy::new(),
+      orphans: [const { AtomicU64::new(0) }; SPANS_PER_SEGMENT / 64],
+      thp_regions: [const { AtomicU64::new(0) }; THP_REGIONS_PER_SEGMENT.div_ceil(64)],
     }
   }
 }
 
+/// Segment table. Zero-initialized, so slots never reserved cost no memory.
+static SEGMENTS: [Segment; MAX_SEGMENTS] = [const { Segment::new() }; MAX_SEGMENTS];
+
+/// Reserved segments; `SEGMENTS[..SEGMENT_COUNT]` are live and never released.
+static SEGMENT_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Serializes segment reservation.
+static SEGMENT_LOCK: SpinLock = SpinLock::new();
+
+/// Segments the arena may reserve (`arena_size` option).
+static SEGMENT_LIMIT: AtomicUsize = AtomicUsize::new(ARENA_SIZE / SEGMENT_SIZE);
+
+/// `address >> SEGMENT_SIZE_BITS` -> segment number + 1 (0 = not an arena address).
+static SEGMENT_MAP: [AtomicU8; 1 << (ADDRESS_BITS - SEGMENT_SIZE_BITS)] =
+  [const { AtomicU8::new(0) }; 1 << (ADDRESS_BITS - SEGMENT_SIZE_BITS)];
+
+/// Live segments, in reservation order.
+fn segments() -> impl Iterator<Item = &'static Segment> {
+  SEGMENTS[..SEGMENT_COUNT.load(Ordering::Acquire)].iter()
+}
+
+/// Reserve one more segment, unless another thread already grew the table past `seen`
+/// segments. Returns false when the arena cannot grow.
+#[cold]
+fn reserve_segment(arena: &Arena, seen: usize) -> bool {
+  SEGMENT_LOCK.lock();
+  let count = SEGMENT_COUNT.load(Ordering::Acquire);
+  let limit = SEGMENT_LIMIT.load(Ordering::Relaxed);
+  let grown = count != seen || (count < limit && unsafe { map_segment(arena, count) });
+  SEGMENT_LOCK.unlock();
+  grown
+}
+
+/// Map segment number `n` for `arena` (caller holds `SEGMENT_LOCK`).
+unsafe fn map_segment(arena: &Arena, n: usize) -> bool {
+  // Segment-aligned, so the segment of an address is its top bits.
+  let base = unsafe { os_mmap_aligned(SEGMENT_SIZE, SEGMENT_SIZE, 0) };
+  if base.is_null() {
+    return false;
+  }
+  let key = base as usize >> SEGMENT_SIZE_BITS;
+  if key >= SEGMENT_MAP.len() || !page_map_reserve(base as usize, SEGMENT_SIZE) {
+    unsafe { os_munmap(base, SEGMENT_SIZE) };
+    return false;
+  }
+  if THP_POLICY.load(Ordering::Relaxed) != THP_POLICY_SYSTEM {
+    unsafe { libc::madvise(base.cast(), SEGMENT_SIZE, libc::MADV_NOHUGEPAGE) };
+  }
+  let config = config();
+  if config.nodes > 1 && !config.fake_numa {
+    unsafe { os_bind_node(base, SEGMENT_SIZE, arena.node) };
+  }
+
+  let segment = &SEGMENTS[n];
+  segment.base.store(base, Ordering::Release);
+  segment.node.store(arena.node, Ordering::Relaxed);
+  SEGMENT_MAP[key].store(n as u8 + 1, Ordering::Release);
+  segment.buddy.init(arena, n << BUDDY_MAX_ORDER);
+  SEGMENT_COUNT.store(n + 1, Ordering::Release);
+  true
+}
+
 // =============================================================================
 // Arena
 // =============================================================================
 
+/// Span caches and segments of one NUMA node.
 struct Arena {
-  base: AtomicPtr<u8>,
-  buddy: Buddy,
+  node: usize,
   cache: GlobalCache,
   reuse: ReuseCache,
+  empty: EmptyPool,
+  /// Shards of each shared cache (power of two, `shards` option).
+  shards: usize,
 }
 
 unsafe impl Sync for Arena {}
 unsafe impl Send for Arena {}
 
-static ARENA: OnceLock<Arena> = OnceLock::new();
+/// One arena per NUMA node, created on first use.
+static ARENAS: [OnceLock<Arena>; MAX_NUMA_NODES] = [const { OnceLock::new() }; MAX_NUMA_NODES];
+
+/// Arenas created so far.
+fn arenas() -> impl Iterator<Item = &'static Arena> {
+  ARENAS.iter().filter_map(OnceLock::get)
+}
 
 impl Arena {
-  const fn new() -> Self {
+  fn new(node: usize) -> Self {
+    let shards = config().shards;
     Self {
-      base: AtomicPtr::new(null_mut()),
-      buddy: Buddy::new(),
-      cache: GlobalCache::new(),
-      reuse: ReuseCache::new(),
+      node,
+      cache: GlobalCache::new(shards),
+      reuse: ReuseCache::new(shards),
+      empty: EmptyPool::new(shards),
+      shards,
     }
   }
 
+  /// Arena of the calling CPU's node.
   fn get() -> Option<&'static Self> {
-    Some(ARENA.get_or_init(|| {
-      // Over-allocate for alignment padding.
-      let raw = unsafe { os_mmap(ARENA_SIZE + SPAN_SIZE) };
-      if raw.is_null() {
-        panic!("Arena mmap failed");
-      }
-
-      let aligned = align_up(raw as usize, SPAN_SIZE) as *mut u8;
+    Self::for_node(config().cpu_node[cpu_id() & (MAX_CPUS - 1)] as usize)
+  }
 
-      let arena = Arena::new();
-      arena.base.store(aligned, Ordering::Release);
-      arena.buddy.init(aligned);
-      arena
-    }))
+  fn for_node(node: usize) -> Option<&'static Self> {
+    Some(ARENAS[node].get_or_init(|| Arena::new(node)))
   }
 
+  /// Arena owning the segment `ptr` points into, if any.
   #[inline]
-  fn idx_to_span(&self, idx: usize) -> *mut SpanHeader {
-    unsafe { self.base.load(Ordering::Relaxed).add(idx << SPAN_SIZE_BITS) as *mut SpanHeader }
+  fn owner(ptr: *mut u8) -> Option<&'static Self> {
+    let n = SEGMENT_MAP
+      .get(ptr as usize >> SEGMENT_SIZE_BITS)?
+      .load(Ordering::Relaxed);
+    if n == 0 {
+      return None;
+    }
+    ARENAS[SEGMENTS[n as usize - 1].node.load(Ordering::Relaxed)].get()
+  }
+
+  /// Segments owned by this arena, with their segment number.
+  fn own_segments(&self) -> impl Iterator<Item = (usize, &'static Segment)> {
+    segments()
+      .enumerate()
+      .filter(move |(_, segment)| segment.node.load(Ordering::Relaxed) == self.node)
   }
 
   #[inline]
-  fn span_to_idx(&self, span: *mut SpanHeader) -> usize {
-    (span as usize - self.base.load(Ordering::Relaxed) as usize) >> SPAN_SIZE_BITS
+  fn segment(&self, idx: usize) -> &'static Segment {
+    &SEGMENTS[idx >> BUDDY_MAX_ORDER]
   }
 
   #[inline]
-  fn ptr_to_span(&self, ptr: *mut u8) -> *mut SpanHeader {
-    (ptr as usize & SPAN_ALIGN_MASK) as *mut SpanHeader
+  fn idx_to_span(&self, idx: usize) -> *mut SpanHeader {
+    let base = self.segment(idx).base.load(Ordering::Relaxed);
+    unsafe { base.add((idx & (SPANS_PER_SEGMENT - 1)) << SPAN_SIZE_BITS) as *mut SpanHeader }
   }
 
   #[inline]
-  fn contains(&self, ptr: *mut u8) -> bool {
-    let base = self.base.load(Ordering::Relaxed) as usize;
-    let addr = ptr as usize;
-    addr >= base && addr < base + ARENA_SIZE
+  fn span_to_idx(&self, span: *mut SpanHeader) -> usize {
+    let addr = span as usize;
+    let n = SEGMENT_MAP[addr >> SEGMENT_SIZE_BITS].load(Ordering::Relaxed) as usize - 1;
+    (n << BUDDY_MAX_ORDER) | ((addr & (SEGMENT_SIZE - 1)) >> SPAN_SIZE_BITS)
   }
 
   #[inline]
@@ -687,32 +2013,123 @@ impl Arena {
     if ptr.is_null() {
       return true; // null is valid
     }
-    let base = self.base.load(Ordering::Relaxed) as usize;
-    let addr = ptr as usize;
-    ptr.is_aligned() && addr >= base && addr < base + ARENA_SIZE
+    ptr.is_aligned() && Self::owner(ptr.cast()).is_some()
   }
 
-  #[inline(never)]
-  fn global_pop(&self, cpu: usize, class: usize) -> *mut SpanHeader {
-    let start = cpu & (SHARD_COUNT - 1);
-    for i in 0..hint::black_box(SHARD_COUNT) {
-      let span_ptr = self.cache.pop((start + i) & (SHARD_COUNT - 1), class);
-      if !span_ptr.is_null() {
-        return span_ptr;
+  /// Allocate a buddy block of `1 << order` spans from the first segment of this arena that
+  /// has one, reserving a new segment when all are full.
+  fn buddy_alloc(&self, order: usize) -> Option<usize> {
+    decay();
+    if !budget_admit(SPAN_SIZE << order) {
+      return None;
+    }
+
+    loop {
+      let count = SEGMENT_COUNT.load(Ordering::Acquire);
+      for segment in &SEGMENTS[..count] {
+        if segment.node.load(Ordering::Relaxed) == self.node
+          && let Some(idx) = segment.buddy.alloc(self, order)
+        {
+          let span = self.idx_to_span(idx);
+          page_map_set(span as usize, SPAN_SIZE << order, span);
+          return Some(idx);
+        }
+      }
+      if !reserve_segment(self, count) {
+        return None;
       }
     }
-    null_mut()
   }
 
+  /// Return a buddy block to its segment. See [`Buddy::free`] for `release`.
+  #[inline]
+  fn buddy_free(&self, idx: usize, order: usize, release: bool) {
+    page_map_set(
+      self.idx_to_span(idx) as usize,
+      SPAN_SIZE << order,
+      null_mut(),
+    );
+    self.segment(idx).buddy.free(self, idx, order, release);
+  }
+
+  /// Allocate a run of `spans` spans: the buddy block covering it, whose blocks past the run go
+  /// straight back.
+  fn buddy_alloc_run(&self, spans: usize) -> Option<usize> {
+    let covering = spans.next_power_of_two();
+    let idx = self.buddy_alloc(covering.trailing_zeros() as usize)?;
+    let committed = unsafe { (*self.idx_to_span(idx)).committed };
+    self.buddy_free_range(idx + spans, idx + covering, committed);
+    Some(idx)
+  }
+
+  /// Return the spans `[start, end)` to their segment as the buddy blocks that tile them.
+  /// `committed` is stamped on each block header first: the headers inside a run are stale.
+  fn buddy_free_range(&self, start: usize, end: usize, committed: bool) {
+    for (idx, order) in buddy_pieces(start, end) {
+      unsafe { (*self.idx_to_span(idx)).committed = committed };
+      self.buddy_free(idx, order, false);
+    }
+  }
+
+  /// Grow the in-use run at `idx` in place. See [`Buddy::grow`].
+  fn buddy_grow(&self, idx: usize, spans: usize, new_spans: usize) -> bool {
+    let extra = (new_spans - spans) * SPAN_SIZE;
+    if !budget_admit(extra) || !self.segment(idx).buddy.grow(self, idx, spans, new_spans) {
+      return false;
+    }
+    let span = self.idx_to_span(idx);
+    page_map_set(span as usize + spans * SPAN_SIZE, extra, span);
+    true
+  }
+
+  /// Coalesce the free lists of every segment of this arena. Returns the number of merges.
+  fn coalesce(&self) -> usize {
+    self
+      .own_segments()
+      .map(|(_, segment)| segment.buddy.coalesce(self))
+      .sum()
+  }
+
+  #[inline(never)]
+  fn global_pop(&self, cpu: usize, class: usize) -> *mut SpanHeader {
+    let start = cpu & (self.shards - 1);
+    for i in 0..hint::black_box(self.shards) {
+      let span_ptr = self.cache.pop((start + i) & (self.shards - 1), class);
+      if !span_ptr.is_null() {
+        return span_ptr;
+      }
+    }
+    null_mut()
+  }
+
+  /// Cache a fully free span: warm in its class cache, or in the empty pool if that is full.
+  /// Medium spans do not fit other classes and go back to the buddy allocator instead.
   fn global_push(&self, cpu: usize, class: usize, span: *mut SpanHeader) {
-    self.cache.push(cpu & (SHARD_COUNT - 1), class, span);
+    if !self.cache.push(cpu & (self.shards - 1), class, span) {
+      match class_order(class) {
+        0 => self.empty.push(cpu, span),
+        order => self.buddy_free(self.span_to_idx(span), order, false),
+      }
+    }
+  }
+
+  #[inline(never)]
+  fn empty_pop(&self, cpu: usize) -> *mut SpanHeader {
+    let start = cpu & (self.shards - 1);
+    for i in 0..hint::black_box(self.shards) {
+      let span_ptr = self.empty.pop((start + i) & (self.shards - 1));
+      if !span_ptr.is_null() {
+        return span_ptr;
+      }
+    }
+    null_mut()
   }
 
   #[inline(never)]
   fn reuse_pop(&self, cpu: usize, class: usize) -> *mut SpanHeader {
-    let start = cpu & (SHARD_COUNT - 1);
-    for i in 0..hint::black_box(SHARD_COUNT) {
-      let span_ptr = self.reuse.pop((start + i) & (SHARD_COUNT - 1), class);
+    let start = cpu & (self.shards - 1);
+    for i in 0..hint::black_box(self.shards) {
+      let span_ptr = self.reuse.pop((start + i) & (self.shards - 1), class);
       if !span_ptr.is_null() {
         return span_ptr;
       }
@@ -720,30 +2137,199 @@ impl Arena {
     null_mut()
   }
 
-  fn reuse_push(&self, cpu: usize, class: usize, span: *mut SpanHeader) {
-    if GLOBAL_ACTIVE_SPAN_COUNTER.load(Ordering::Relaxed) > MAX_GLOBAL_ACTIVE_SPANS {
-      return;
+  /// Returns true if the span is now in the reuse cache.
+  fn reuse_push(&self, cpu: usize, class: usize, span: *mut SpanHeader) -> bool {
+    if GLOBAL_ACTIVE_SPAN_COUNTER.load(Ordering::Relaxed) > MAX_ACTIVE_SPANS.load(Ordering::Relaxed)
+    {
+      return false;
     }
 
     let already = unsafe { (*span).in_reuse.swap(true, Ordering::AcqRel) };
     if already {
-      return; // Someone else pushed it.
+      return false; // Someone else pushed it.
     }
 
     // Re-verify owner after acquiring lock. If changed, we raced
     if unsafe { (*span).owner.load(Ordering::Acquire) } != SPAN_OWNER_ORPHAN {
       unsafe { (*span).in_reuse.store(false, Ordering::Release) };
-      return;
+      return false;
     }
 
-    if !self.reuse.push(cpu & (SHARD_COUNT - 1), class, span) {
+    if !self.reuse.push(cpu & (self.shards - 1), class, span) {
       unsafe { (*span).in_reuse.store(false, Ordering::Release) };
+      return false;
+    }
+
+    self.orphan_clear(span);
+    true
+  }
+
+  /// Hand off an orphan span whose last block was just freed. `in_reuse` elects a single
+  /// thread for the cleanup.
+  unsafe fn release_orphan(&self, span: *mut SpanHeader) {
+    unsafe {
+      if (*span).owner.load(Ordering::Acquire) != SPAN_OWNER_ORPHAN
+        || (*span).kind != SpanKind::Small
+      {
+        // If owner != ORPHAN, the owning thread will handle via retire_small_span
+        return;
+      }
+
+      // This prevents double-enqueue and ensures only one thread handles cleanup.
+      if (*span).in_reuse.swap(true, Ordering::AcqRel) {
+        return;
+      }
+
+      // Re-verify owner after acquiring lock. If changed, we raced.
+      if (*span).owner.load(Ordering::Acquire) != SPAN_OWNER_ORPHAN {
+        // Span was claimed. Restore and abort.
+        (*span).in_reuse.store(false, Ordering::Release);
+        return;
+      }
+
+      let class = (*span).class as usize;
+      let cpu = cpu_id();
+      // Try reuse cache first or fallback to global cache.
+      if !self.reuse.push(cpu & (self.shards - 1), class, span) {
+        self.global_push(cpu, class, span);
+      }
+      self.orphan_clear(span);
+    }
+  }
+
+  #[inline]
+  fn orphan_mark(&self, span: *mut SpanHeader) {
+    let idx = self.span_to_idx(span);
+    let local = idx & (SPANS_PER_SEGMENT - 1);
+    self.segment(idx).orphans[local / 64].fetch_or(1 << (local % 64), Ordering::Release);
+  }
+
+  #[inline]
+  fn orphan_clear(&self, span: *mut SpanHeader) {
+    let idx = self.span_to_idx(span);
+    let local = idx & (SPANS_PER_SEGMENT - 1);
+    let word = &self.segment(idx).orphans[local / 64];
+    let bit = 1 << (local % 64);
+    if word.load(Ordering::Relaxed) & bit != 0 {
+      word.fetch_and(!bit, Ordering::Release);
+    }
+  }
+
+  /// Give orphan spans left outside every cache another chance: fully free ones are cached,
+  /// ones with remote frees go to the reuse cache. The rest stay marked for the next scan.
+  /// Returns the number of spans moved into a cache.
+  #[cfg(feature = "background")]
+  fn reclaim_orphans(&self) -> usize {
+    let mut reclaimed = 0;
+    let words = self.own_segments().flat_map(|(n, segment)| {
+      let first = n << BUDDY_MAX_ORDER;
+      segment
+        .orphans
+        .iter()
+        .enumerate()
+        .map(move |(i, word)| (first + i * 64, word))
+    });
+    for (word_idx, word) in words {
+      if word.load(Ordering::Relaxed) == 0 {
+        continue;
+      }
+
+      let mut bits = word.swap(0, Ordering::AcqRel);
+      while bits != 0 {
+        let span = self.idx_to_span(word_idx + bits.trailing_zeros() as usize);
+        bits &= bits - 1;
+
+        unsafe {
+          // Claim it the way `reuse_push` does; a set flag means it is cached or being handled.
+          if (*span).in_reuse.swap(true, Ordering::AcqRel) {
+            continue;
+          }
+          if (*span).owner.load(Ordering::Acquire) != SPAN_OWNER_ORPHAN
+            || (*span).kind != SpanKind::Small
+          {
+            (*span).in_reuse.store(false, Ordering::Release);
+            continue;
+          }
+
+          let class = (*span).class as usize;
+          let cpu = cpu_id();
+          if (*span).used.load(Ordering::Acquire) == 0 {
+            // The last free raced with our claim and backed off: finish its job.
+            if !self.reuse.push(cpu & (self.shards - 1), class, span) {
+              self.global_push(cpu, class, span);
+            }
+            reclaimed += 1;
+            continue;
+          }
+
+          if !(*span).remote_free.load(Ordering::Acquire).is_null()
+            && GLOBAL_ACTIVE_SPAN_COUNTER.load(Ordering::Relaxed)
+              <= MAX_ACTIVE_SPANS.load(Ordering::Relaxed)
+            && self.reuse.push(cpu & (self.shards - 1), class, span)
+          {
+            reclaimed += 1;
+            continue;
+          }
+
+          self.orphan_mark(span);
+          (*span).in_reuse.store(false, Ordering::Release);
+          // A last free may have backed off while we held the flag.
+          if (*span).used.load(Ordering::Acquire) == 0 {
+            self.release_orphan(span);
+          }
+        }
+      }
+    }
+    reclaimed
+  }
+
+  fn orphan_count(&self) -> usize {
+    self
+      .own_segments()
+      .flat_map(|(_, segment)| &segment.orphans)
+      .map(|w| w.load(Ordering::Relaxed).count_ones() as usize)
+      .sum()
+  }
+
+  /// Advise the 2MB region of a span about to serve `class` for huge pages, if the class is hot
+  /// under [`ThpPolicy::HotClasses`]. One `madvise` per region, before its payload is touched.
+  #[inline]
+  fn thp_advise(&self, span: *mut SpanHeader, class: usize) {
+    if THP_POLICY.load(Ordering::Relaxed) != THP_POLICY_HOT
+      || class_to_size(class) > THP_HOT_MAX_SIZE
+    {
+      return;
     }
+    let idx = self.span_to_idx(span);
+    let region = (idx & (SPANS_PER_SEGMENT - 1)) >> THP_REGION_ORDER;
+    let bit = 1u64 << (region % 64);
+    let word = &self.segment(idx).thp_regions[region / 64];
+    if word.load(Ordering::Relaxed) & bit == 0 && word.fetch_or(bit, Ordering::Relaxed) & bit == 0 {
+      let start = self.idx_to_span(idx & !((1 << THP_REGION_ORDER) - 1));
+      unsafe { libc::madvise(start.cast(), THP_REGION_SIZE, libc::MADV_HUGEPAGE) };
+    }
+  }
+
+  /// Whether the span at `idx` lies in a region advised for huge pages.
+  #[inline]
+  fn thp_advised(&self, idx: usize) -> bool {
+    let region = (idx & (SPANS_PER_SEGMENT - 1)) >> THP_REGION_ORDER;
+    self.segment(idx).thp_regions[region / 64].load(Ordering::Relaxed) & (1 << (region % 64)) != 0
+  }
+
+  fn thp_advised_count(&self) -> usize {
+    self
+      .own_segments()
+      .flat_map(|(_, segment)| &segment.thp_regions)
+      .map(|w| w.load(Ordering::Relaxed).count_ones() as usize)
+      .sum()
   }
 
   /// Get a small `Span` prepared for allocation:
   #[inline(never)]
   fn get_span_small(&self, heap: &mut ThreadHeap, class: usize) -> *mut SpanHeader {
+    heap.check_purge(self);
+
     // 1) Local cache
     let span_ptr = heap.cache_pop(class);
     if !span_ptr.is_null() {
@@ -753,6 +2339,7 @@ impl Arena {
 
     // 2) Global cache
     heap.cpu = cpu_id();
+    decay();
     let span_ptr = self.global_pop(heap.cpu, class);
     if !span_ptr.is_null() {
       unsafe { init_span(span_ptr, class, heap.tid) };
@@ -812,26 +2399,111 @@ impl Arena {
       return span_ptr;
     }
 
-    // 4) Buddy
+    // 4) Empty pool (fully free spans of any small class)
+    let order = class_order(class);
+    if order == 0 {
+      let span_ptr = self.empty_pop(heap.cpu);
+      if !span_ptr.is_null() {
+        self.thp_advise(span_ptr, class);
+        unsafe { init_span(span_ptr, class, heap.tid) };
+        return span_ptr;
+      }
+    }
+
+    // 5) Buddy
     self
-      .buddy
-      .alloc(self, 0)
+      .buddy_alloc(order)
       .map(|idx| self.idx_to_span(idx))
-      .map(|span_ptr| {
+      .inspect(|&span_ptr| {
         // Fresh buddy spans need used=0 (cached spans already verified used==0)
         unsafe { (*span_ptr).used.store(0, Ordering::Relaxed) };
+        self.thp_advise(span_ptr, class);
         unsafe { init_span(span_ptr, class, heap.tid) };
-        span_ptr
       })
       .unwrap_or(null_mut())
   }
 
+  /// Return fully free spans cached since `cutoff` (ms) or earlier to the buddy allocator,
+  /// which coalesces and decommits them, then decommit buddy blocks left committed since
+  /// `cutoff`. At most `limit` spans leave each cache stack and `limit` buddy blocks are
+  /// scanned per order (`usize::MAX` purges everything). Returns the number of bytes released.
+  fn purge(&self, cutoff: u64, limit: usize) -> usize {
+    let stacks = (self.empty.heads.iter().zip(self.empty.counts.iter()))
+      .chain(self.cache.heads.iter().zip(self.cache.counts.iter()))
+      .chain(self.reuse.heads.iter().zip(self.reuse.counts.iter()));
+    let released: usize = stacks
+      .map(|(stack, count)| self.purge_stack(stack, count, cutoff, limit))
+      .sum();
+    released
+      + self
+        .own_segments()
+        .map(|(_, segment)| segment.buddy.release_committed(self, cutoff, limit))
+        .sum::<usize>()
+  }
+
+  /// Purge up to `limit` idle spans from one cache stack. Partially used spans (reuse cache)
+  /// always go back. The stack is detached only while it is sorted into kept and idle spans;
+  /// the kept ones go back in one push, in their original order, before any span is freed.
+  /// `in_reuse` stays set on released spans so a late `free_small` cannot enqueue them again.
+  /// Returns the number of bytes released.
+  fn purge_stack(
+    &self,
+    stack: &SpanStack,
+    count: &AtomicUsize,
+    cutoff: u64,
+    limit: usize,
+  ) -> usize {
+    if stack.is_empty() {
+      return 0;
+    }
+
+    let (mut kept, mut kept_tail) = (null_mut::<SpanHeader>(), null_mut::<SpanHeader>());
+    let (mut idle, mut idle_count) = (null_mut::<SpanHeader>(), 0);
+    let mut span = stack.take_all();
+    while !span.is_null() {
+      let next = unsafe { (*span).cache_next };
+      let stale = idle_count < limit
+        && unsafe { (*span).used.load(Ordering::Acquire) == 0 && (*span).last_used <= cutoff };
+      unsafe {
+        if stale {
+          (*span).cache_next = idle;
+          idle = span;
+          idle_count += 1;
+        } else {
+          (*span).cache_next = null_mut();
+          if kept_tail.is_null() {
+            kept = span;
+          } else {
+            (*kept_tail).cache_next = span;
+          }
+          kept_tail = span;
+        }
+      }
+      span = next;
+    }
+    if !kept.is_null() {
+      stack.push_list(kept, kept_tail);
+    }
+
+    let mut released = 0;
+    while !idle.is_null() {
+      let next = unsafe { (*idle).cache_next };
+      count.fetch_sub(1, Ordering::Relaxed);
+      let order = unsafe { (*idle).order as usize };
+      self.buddy_free(self.span_to_idx(idle), order, true);
+      released += SPAN_SIZE << order;
+      idle = next;
+    }
+    released
+  }
+
   /// Retire a small span: publish freelists, mark orphan, cache or return to buddy.
   unsafe fn retire_small_span(&self, heap: &mut ThreadHeap, span: *mut SpanHeader) {
     debug_assert!(!span.is_null());
     debug_assert!(unsafe { (*span).kind } == SpanKind::Small);
 
     let class = unsafe { (*span).class as usize };
+    decay();
 
     // Publish local freelists to remote_free.
     unsafe {
@@ -850,6 +2522,8 @@ impl Arena {
         push_remote_list(&(*span).remote_free, list);
       }
 
+      // Mark before orphaning, so whoever caches the span afterwards clears the bit.
+      self.orphan_mark(span);
       (*span).owner.store(SPAN_OWNER_ORPHAN, Ordering::Release);
     }
 
@@ -860,8 +2534,9 @@ impl Arena {
         (*span).remote_free.store(null_mut(), Ordering::Relaxed);
       }
 
+      self.orphan_clear(span);
       let active = GLOBAL_ACTIVE_SPAN_COUNTER.load(Ordering::Relaxed);
-      if active <= MAX_GLOBAL_ACTIVE_SPANS && heap.cache_push(class, span) {
+      if active <= MAX_ACTIVE_SPANS.load(Ordering::Relaxed) && heap.cache_push(class, span) {
         return;
       }
 
@@ -898,125 +2573,649 @@ unsafe fn push_remote_list(head: &AtomicPtr<FreeBlock>, list: *mut FreeBlock) {
 }
 
 // =============================================================================
-// TLS
+// Stats
 // =============================================================================
 
-thread_local! {
-  static HEAP: UnsafeCell<ThreadHeap> = UnsafeCell::new(ThreadHeap::new());
-  static IN_ALLOC: Cell<bool> = const { Cell::new(false) };
+/// Point-in-time allocator statistics. Counters are read without a global lock, so they are
+/// individually accurate but not a consistent snapshot.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stats {
+  /// Monotonic time (ms) the stats were collected.
+  pub timestamp_ms: u64,
+  /// Arena segments reserved (1GB of address space each).
+  pub segments: usize,
+  /// Arenas in use (one per NUMA node with allocating threads).
+  pub arenas: usize,
+  /// Spans handed out by the buddy allocator (in use or cached above it).
+  pub active_spans: usize,
+  /// Bytes mapped for huge allocations, including the huge cache.
+  pub huge_mapped_bytes: usize,
+  /// Huge mappings, including those in the huge cache.
+  pub huge_mappings: usize,
+  /// Bytes of freed huge mappings kept for reuse.
+  pub huge_cached_bytes: usize,
+  /// Huge allocations served from the huge cache.
+  pub huge_cache_hits: usize,
+  /// Huge allocations that had to map fresh memory.
+  pub huge_cache_misses: usize,
+  /// Free buddy blocks per order (order `n` is `64KB << n`), summed over segments.
+  pub buddy_free_blocks: [usize; BUDDY_MAX_ORDER + 1],
+  /// Address space reserved by free buddy blocks, per order.
+  pub buddy_reserved_bytes: [usize; BUDDY_MAX_ORDER + 1],
+  /// Free buddy blocks whose payload is still committed (resident or cheap to reuse), per order.
+  pub buddy_committed_bytes: [usize; BUDDY_MAX_ORDER + 1],
+  /// Fully free spans parked in the per-class global cache.
+  pub global_cached_spans: usize,
+  /// Bytes of the spans in the global cache (a medium span covers several 64KB spans).
+  pub global_cached_bytes: usize,
+  /// Fully free spans parked in the class-agnostic empty pool.
+  pub empty_cached_spans: usize,
+  /// Orphan spans with remote frees parked in the reuse cache.
+  pub reuse_cached_spans: usize,
+  /// Orphan spans outside every cache, waiting for their blocks to be freed.
+  pub orphan_spans: usize,
+  /// Memory pressure events handled (`pressure` feature).
+  pub pressure_events: usize,
+  /// 2MB arena regions advised `MADV_HUGEPAGE` ([`ThpPolicy::HotClasses`]). See
+  /// [`thp_backed_regions`] for how many are actually backed by a huge page.
+  pub thp_advised_regions: usize,
 }
 
-fn with_heap<R: Default, F: FnOnce(&mut ThreadHeap, &Arena) -> R>(f: F) -> R {
-  // For dynamic linking (LD_PRELOAD), TLS may be destroyed during exit.
-  // We use try_with to avoid panicking when TLS is being destroyed.
-  #[cfg(feature = "dynamic")]
-  {
-    // Try to access IN_ALLOC; if TLS is destroyed, return default
-    let Ok(in_alloc) = IN_ALLOC.try_with(|flag| flag.get()) else {
-      return R::default();
-    };
+/// Collect allocator statistics now, summed over every arena.
+pub fn stats() -> Stats {
+  let mut stats = Stats {
+    timestamp_ms: now_ms(),
+    segments: SEGMENT_COUNT.load(Ordering::Relaxed),
+    arenas: arenas().count(),
+    active_spans: GLOBAL_ACTIVE_SPAN_COUNTER.load(Ordering::Relaxed),
+    huge_mapped_bytes: HUGE_MAPPED_BYTES.load(Ordering::Relaxed),
+    huge_mappings: HUGE_MAPPINGS.load(Ordering::Relaxed),
+    huge_cached_bytes: HUGE_CACHED_BYTES.load(Ordering::Relaxed),
+    huge_cache_hits: HUGE_CACHE_HITS.load(Ordering::Relaxed),
+    huge_cache_misses: HUGE_CACHE_MISSES.load(Ordering::Relaxed),
+    pressure_events: PRESSURE_EVENTS.load(Ordering::Relaxed),
+    ..Stats::default()
+  };
 
-    if in_alloc {
-      return R::default();
+  let mut committed = [0; BUDDY_MAX_ORDER + 1];
+  for segment in segments() {
+    let (free, comm) = segment.buddy.free_counts();
+    for order in 0..=BUDDY_MAX_ORDER {
+      stats.buddy_free_blocks[order] += free[order];
+      committed[order] += comm[order];
     }
+  }
+  let free = stats.buddy_free_blocks;
+  stats.buddy_reserved_bytes = core::array::from_fn(|o| free[o] * (SPAN_SIZE << o));
+  stats.buddy_committed_bytes = core::array::from_fn(|o| committed[o] * (SPAN_SIZE << o));
+
+  for arena in arenas() {
+    stats.global_cached_spans += arena.cache.len();
+    stats.global_cached_bytes += arena.cache.bytes();
+    stats.empty_cached_spans += arena.empty.len();
+    stats.reuse_cached_spans += arena.reuse.len();
+    stats.orphan_spans += arena.orphan_count();
+    stats.thp_advised_regions += arena.thp_advised_count();
+  }
+  stats
+}
 
-    // Set re-entrancy guard
-    let _ = IN_ALLOC.try_with(|flag| flag.set(true));
+// =============================================================================
+// Trim
+// =============================================================================
 
-    let result = HEAP
-      .try_with(|h| {
-        let heap = unsafe { &mut *h.get() };
-        Arena::get().map(|a| f(heap, a)).unwrap_or_default()
-      })
-      .unwrap_or_default();
+/// Give memory back now: return the calling thread's cached spans and every fully free span
+/// in the shared caches to the buddy allocator, where they coalesce and their pages are
+/// released (`release-mem`). Returns the number of bytes released.
+pub fn trim() -> usize {
+  let flushed = with_heap(|heap, arena| heap.flush_cache(arena));
+  let mut purged = huge_cache_purge(u64::MAX);
+  for arena in arenas() {
+    purged += arena.purge(u64::MAX, usize::MAX);
+    arena.coalesce();
+  }
+  flushed + purged
+}
 
-    let _ = IN_ALLOC.try_with(|flag| flag.set(false));
-    result
+// =============================================================================
+// Background Thread (enabled with --features background)
+// =============================================================================
+
+/// Maintenance state. The thread is created with `pthread_create` and only touches the
+/// arena directly, so it never allocates through `with_heap`.
+#[cfg(feature = "background")]
+struct Background {
+  /// Serializes start/stop.
+  control: UnsafeCell<libc::pthread_mutex_t>,
+  /// Protects `stop` waits.
+  mutex: UnsafeCell<libc::pthread_mutex_t>,
+  cond: UnsafeCell<libc::pthread_cond_t>,
+  thread: UnsafeCell<libc::pthread_t>,
+  running: AtomicBool,
+  stop: AtomicBool,
+  atexit_registered: AtomicBool,
+}
+
+#[cfg(feature = "background")]
+unsafe impl Sync for Background {}
+
+#[cfg(feature = "background")]
+static BACKGROUND: Background = Background {
+  control: UnsafeCell::new(libc::PTHREAD_MUTEX_INITIALIZER),
+  mutex: UnsafeCell::new(libc::PTHREAD_MUTEX_INITIALIZER),
+  cond: UnsafeCell::new(libc::PTHREAD_COND_INITIALIZER),
+  thread: UnsafeCell::new(0),
+  running: AtomicBool::new(false),
+  stop: AtomicBool::new(false),
+  atexit_registered: AtomicBool::new(false),
+};
+
+/// Last stats published by the background thread.
+#[cfg(feature = "background")]
+static STATS_SNAPSHOT: std::sync::Mutex<Option<Stats>> = std::sync::Mutex::new(None);
+
+/// Stats published by the background thread on its last tick, if it ever ran.
+#[cfg(feature = "background")]
+pub fn stats_snapshot() -> Option<Stats> {
+  *STATS_SNAPSHOT.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Start or stop the background maintenance thread. Every 100ms it decay-purges the span
+/// caches, coalesces buddy free lists, reclaims orphan spans and publishes [`stats_snapshot`].
+/// The thread is stopped and joined at process exit. Returns whether the thread is running.
+#[cfg(feature = "background")]
+pub fn set_background_thread(enabled: bool) -> bool {
+  let bg = &BACKGROUND;
+  unsafe { libc::pthread_mutex_lock(bg.control.get()) };
+
+  if enabled && !bg.running.load(Ordering::Relaxed) {
+    bg.stop.store(false, Ordering::Relaxed);
+    let ok = unsafe {
+      libc::pthread_create(bg.thread.get(), ptr::null(), background_main, null_mut()) == 0
+    };
+    bg.running.store(ok, Ordering::Relaxed);
+    if ok && !bg.atexit_registered.swap(true, Ordering::Relaxed) {
+      unsafe { libc::atexit(background_atexit) };
+    }
+  } else if !enabled && bg.running.load(Ordering::Relaxed) {
+    unsafe {
+      libc::pthread_mutex_lock(bg.mutex.get());
+      bg.stop.store(true, Ordering::Relaxed);
+      libc::pthread_cond_signal(bg.cond.get());
+      libc::pthread_mutex_unlock(bg.mutex.get());
+      libc::pthread_join(*bg.thread.get(), null_mut());
+    }
+    bg.running.store(false, Ordering::Relaxed);
   }
 
-  #[cfg(not(feature = "dynamic"))]
-  {
-    IN_ALLOC.with(|flag| {
-      if flag.get() {
-        return R::default();
+  let running = bg.running.load(Ordering::Relaxed);
+  unsafe { libc::pthread_mutex_unlock(bg.control.get()) };
+  running
+}
+
+#[cfg(feature = "background")]
+extern "C" fn background_atexit() {
+  set_background_thread(false);
+}
+
+#[cfg(feature = "background")]
+extern "C" fn background_main(_: *mut libc::c_void) -> *mut libc::c_void {
+  let bg = &BACKGROUND;
+  loop {
+    unsafe {
+      let mut deadline = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+      };
+      libc::clock_gettime(libc::CLOCK_REALTIME, &mut deadline);
+      deadline.tv_nsec += (DECAY_INTERVAL_MS * 1_000_000) as libc::c_long;
+      if deadline.tv_nsec >= 1_000_000_000 {
+        deadline.tv_sec += 1;
+        deadline.tv_nsec -= 1_000_000_000;
       }
-      flag.set(true);
 
-      let result = HEAP.with(|h| {
-        let heap = unsafe { &mut *h.get() };
-        Arena::get().map(|a| f(heap, a)).unwrap_or_default()
-      });
+      libc::pthread_mutex_lock(bg.mutex.get());
+      if !bg.stop.load(Ordering::Relaxed) {
+        libc::pthread_cond_timedwait(bg.cond.get(), bg.mutex.get(), &deadline);
+      }
+      let stop = bg.stop.load(Ordering::Relaxed);
+      libc::pthread_mutex_unlock(bg.mutex.get());
+      if stop {
+        return null_mut();
+      }
+    }
 
-      flag.set(false);
-      result
-    })
+    decay();
+    for arena in arenas() {
+      arena.coalesce();
+      arena.reclaim_orphans();
+    }
+    *STATS_SNAPSHOT.lock().unwrap_or_else(|e| e.into_inner()) = Some(stats());
   }
 }
 
 // =============================================================================
-// Small allocation / free
+// Memory Pressure (enabled with --features pressure)
 // =============================================================================
 
-// NOTE: Do NOT reset `used` here. In-flight frees may still be pending.
-// Callers must verify used==0 before calling init_span.
-unsafe fn init_span(span: *mut SpanHeader, class: usize, tid: u32) {
-  let block_size = class_to_size(class);
-  let capacity = (SPAN_SIZE - SPAN_HEADER_SIZE) / block_size;
-  let base = unsafe { (span as *mut u8).add(SPAN_HEADER_SIZE) };
-  let header = unsafe { &mut *span };
-  header.bump = base;
-  header.bump_end = unsafe { base.add(capacity * block_size) };
-  header.hot_block = null_mut();
-  header.local_free = null_mut();
-  header.remote_free.store(null_mut(), Ordering::Relaxed);
-  header.owner.store(tid, Ordering::Release);
-  header.in_reuse.store(false, Ordering::Relaxed);
-  header.block_size = block_size as u32;
-  header.class = class as u8;
-  header.kind = SpanKind::Small;
-  header.order = 0;
-  header.cache_next = null_mut();
-  header.huge_base = null_mut();
-  header.huge_size = 0;
-  header.magic = SPAN_MAGIC;
+/// Number of memory pressure events handled.
+static PRESSURE_EVENTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Default pressure source: the system-wide PSI memory file.
+#[cfg(feature = "pressure")]
+pub const DEFAULT_PRESSURE_SOURCE: &str = "/proc/pressure/memory";
+
+/// PSI trigger: fire when tasks stall on memory for 150ms within a 2s window.
+#[cfg(feature = "pressure")]
+const PRESSURE_PSI_TRIGGER: &[u8] = b"some 150000 2000000\0";
+
+/// Stall time (us) growth between two reads of a plain PSI-formatted file that counts as pressure.
+#[cfg(feature = "pressure")]
+const PRESSURE_PSI_STALL_US: u64 = 150_000;
+
+/// Re-read interval for plain files, which cannot be waited on.
+#[cfg(feature = "pressure")]
+const PRESSURE_POLL_MS: i32 = 1000;
+
+/// How the watcher waits on its source.
+#[cfg(feature = "pressure")]
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+enum PressureKind {
+  /// PSI file (`/proc/pressure/memory`, cgroup `memory.pressure`) with a registered trigger.
+  PsiTrigger = 0,
+  /// cgroup v2 `memory.events`: kernfs signals every change, counters tell what changed.
+  CgroupEvents = 1,
+  /// FIFO: any line written is an event.
+  Pipe = 2,
+  /// Plain file in PSI or `memory.events` format, re-read periodically (tests, fakes).
+  File = 3,
 }
 
-fn alloc_small(heap: &mut ThreadHeap, arena: &Arena, size: usize) -> Option<NonNull<u8>> {
-  let class = size_to_class(size);
+#[cfg(feature = "pressure")]
+struct PressureWatch {
+  /// Serializes start/stop.
+  control: UnsafeCell<libc::pthread_mutex_t>,
+  thread: UnsafeCell<libc::pthread_t>,
+  running: AtomicBool,
+  source_fd: core::sync::atomic::AtomicI32,
+  /// eventfd written to stop the thread.
+  stop_fd: core::sync::atomic::AtomicI32,
+  kind: AtomicU8,
+  atexit_registered: AtomicBool,
+}
 
-  loop {
-    let mut span = heap.spans[class];
-    if span.is_null() {
-      span = arena.get_span_small(heap, class);
-      if span.is_null() {
-        return None;
-      }
-      heap.spans[class] = span;
-    }
+#[cfg(feature = "pressure")]
+unsafe impl Sync for PressureWatch {}
+
+#[cfg(feature = "pressure")]
+static PRESSURE: PressureWatch = PressureWatch {
+  control: UnsafeCell::new(libc::PTHREAD_MUTEX_INITIALIZER),
+  thread: UnsafeCell::new(0),
+  running: AtomicBool::new(false),
+  source_fd: core::sync::atomic::AtomicI32::new(-1),
+  stop_fd: core::sync::atomic::AtomicI32::new(-1),
+  kind: AtomicU8::new(PressureKind::PsiTrigger as u8),
+  atexit_registered: AtomicBool::new(false),
+};
 
-    // Verify we own this span before using it
-    #[cfg(debug_assertions)]
-    {
-      let owner = unsafe { (*span).owner.load(Ordering::Relaxed) };
-      debug_assert!(
-        owner == heap.tid,
-        "alloc_small: span {:p} owner {} != our tid {}! class={}",
-        span,
-        owner,
-        heap.tid,
-        unsafe { (*span).class }
-      );
+/// Watch `path` for memory pressure, or stop watching with `None`. Under pressure every shared
+/// cache is purged, buddy free lists are coalesced and every thread flushes its local span cache
+/// on its next slow path. The source is a PSI file ([`DEFAULT_PRESSURE_SOURCE`] or a cgroup v2
+/// `memory.pressure`), a cgroup v2 `memory.events` file, a FIFO (every write is an event) or a
+/// plain file in either format, re-read every second. Returns whether a source is watched.
+#[cfg(feature = "pressure")]
+pub fn set_pressure_source(path: Option<&str>) -> bool {
+  let watch = &PRESSURE;
+  unsafe { libc::pthread_mutex_lock(watch.control.get()) };
+
+  if watch.running.load(Ordering::Relaxed) {
+    unsafe {
+      libc::eventfd_write(watch.stop_fd.load(Ordering::Relaxed), 1);
+      libc::pthread_join(*watch.thread.get(), null_mut());
+      libc::close(watch.source_fd.swap(-1, Ordering::Relaxed));
+      libc::close(watch.stop_fd.swap(-1, Ordering::Relaxed));
     }
+    watch.running.store(false, Ordering::Relaxed);
+  }
 
-    unsafe {
-      // Fast path: hot block (MRU)
-      let hot = (*span).hot_block;
-      if !hot.is_null() {
-        (*span).hot_block = null_mut();
-        (*span).used.fetch_add(1, Ordering::Relaxed);
-        return NonNull::new(hot);
+  if let Some(path) = path
+    && let Some((fd, kind)) = pressure_open(path)
+  {
+    let stop_fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+    let ok = stop_fd >= 0
+      && unsafe {
+        watch.source_fd.store(fd, Ordering::Relaxed);
+        watch.stop_fd.store(stop_fd, Ordering::Relaxed);
+        watch.kind.store(kind as u8, Ordering::Relaxed);
+        libc::pthread_create(watch.thread.get(), ptr::null(), pressure_main, null_mut()) == 0
+      };
+    if ok {
+      watch.running.store(true, Ordering::Relaxed);
+      if !watch.atexit_registered.swap(true, Ordering::Relaxed) {
+        unsafe { libc::atexit(pressure_atexit) };
       }
-
+    } else {
+      unsafe {
+        libc::close(fd);
+        if stop_fd >= 0 {
+          libc::close(stop_fd);
+        }
+      }
+    }
+  }
+
+  let running = watch.running.load(Ordering::Relaxed);
+  unsafe { libc::pthread_mutex_unlock(watch.control.get()) };
+  running
+}
+
+/// Open a pressure source and work out how to wait on it. No allocation.
+#[cfg(feature = "pressure")]
+fn pressure_open(path: &str) -> Option<(i32, PressureKind)> {
+  let mut cpath = [0u8; 4096];
+  if path.len() >= cpath.len() || path.as_bytes().contains(&0) {
+    return None;
+  }
+  cpath[..path.len()].copy_from_slice(path.as_bytes());
+  let cpath = cpath.as_ptr().cast();
+
+  unsafe {
+    // PSI triggers need write access, FIFOs opened read-write never see POLLHUP.
+    let mut fd = libc::open(cpath, libc::O_RDWR | libc::O_NONBLOCK | libc::O_CLOEXEC);
+    if fd < 0 {
+      fd = libc::open(cpath, libc::O_RDONLY | libc::O_NONBLOCK | libc::O_CLOEXEC);
+    }
+    if fd < 0 {
+      return None;
+    }
+
+    let mut st: libc::stat = core::mem::zeroed();
+    let mut fs: libc::statfs = core::mem::zeroed();
+    if libc::fstat(fd, &mut st) != 0 || libc::fstatfs(fd, &mut fs) != 0 {
+      libc::close(fd);
+      return None;
+    }
+
+    let kernel = fs.f_type == libc::PROC_SUPER_MAGIC || fs.f_type == libc::CGROUP2_SUPER_MAGIC;
+    let kind = if st.st_mode & libc::S_IFMT == libc::S_IFIFO {
+      PressureKind::Pipe
+    } else if !kernel {
+      PressureKind::File
+    } else if path.ends_with("memory.events") {
+      PressureKind::CgroupEvents
+    } else {
+      let len = PRESSURE_PSI_TRIGGER.len();
+      if libc::write(fd, PRESSURE_PSI_TRIGGER.as_ptr().cast(), len) != len as isize {
+        libc::close(fd);
+        return None;
+      }
+      PressureKind::PsiTrigger
+    };
+    Some((fd, kind))
+  }
+}
+
+#[cfg(feature = "pressure")]
+extern "C" fn pressure_atexit() {
+  set_pressure_source(None);
+}
+
+#[cfg(feature = "pressure")]
+extern "C" fn pressure_main(_: *mut libc::c_void) -> *mut libc::c_void {
+  let watch = &PRESSURE;
+  let fd = watch.source_fd.load(Ordering::Relaxed);
+  let stop_fd = watch.stop_fd.load(Ordering::Relaxed);
+  let kind = match watch.kind.load(Ordering::Relaxed) {
+    0 => PressureKind::PsiTrigger,
+    1 => PressureKind::CgroupEvents,
+    2 => PressureKind::Pipe,
+    _ => PressureKind::File,
+  };
+
+  let (events, timeout) = match kind {
+    PressureKind::PsiTrigger | PressureKind::CgroupEvents => (libc::POLLPRI, -1),
+    PressureKind::Pipe => (libc::POLLIN, -1),
+    PressureKind::File => (0, PRESSURE_POLL_MS),
+  };
+
+  // Baseline, so counters accumulated before we started do not count as pressure.
+  let mut last = pressure_read(fd);
+
+  loop {
+    let mut fds = [
+      libc::pollfd {
+        fd,
+        events,
+        revents: 0,
+      },
+      libc::pollfd {
+        fd: stop_fd,
+        events: libc::POLLIN,
+        revents: 0,
+      },
+    ];
+    let n = unsafe { libc::poll(fds.as_mut_ptr(), 2, timeout) };
+    if n < 0 {
+      continue; // EINTR
+    }
+    if fds[1].revents != 0 {
+      return null_mut();
+    }
+
+    let pressure = match kind {
+      PressureKind::PsiTrigger => {
+        if fds[0].revents & libc::POLLERR != 0 {
+          return null_mut(); // Source went away (e.g. cgroup removed).
+        }
+        fds[0].revents & libc::POLLPRI != 0
+      }
+      PressureKind::Pipe => {
+        let mut buf = [0u8; 256];
+        let mut got = false;
+        while unsafe { libc::read(fd, buf.as_mut_ptr().cast(), buf.len()) } > 0 {
+          got = true;
+        }
+        got
+      }
+      PressureKind::CgroupEvents | PressureKind::File => {
+        let now = pressure_read(fd);
+        let grew = match (last, now) {
+          (PressureReading::Events(a), PressureReading::Events(b)) => b > a,
+          (PressureReading::Stall(a), PressureReading::Stall(b)) => b >= a + PRESSURE_PSI_STALL_US,
+          (PressureReading::None, PressureReading::Events(b)) => b > 0,
+          _ => false,
+        };
+        last = now;
+        grew
+      }
+    };
+
+    if pressure {
+      on_memory_pressure();
+    }
+  }
+}
+
+#[cfg(feature = "pressure")]
+#[derive(Clone, Copy)]
+enum PressureReading {
+  None,
+  /// Sum of the `high`, `max`, `oom` and `oom_kill` counters of `memory.events`.
+  Events(u64),
+  /// Cumulative `some` stall time (us) of a PSI file.
+  Stall(u64),
+}
+
+/// Read and parse a pressure source from offset 0 into a stack buffer.
+#[cfg(feature = "pressure")]
+fn pressure_read(fd: i32) -> PressureReading {
+  let mut buf = [0u8; 1024];
+  let n = unsafe { libc::pread(fd, buf.as_mut_ptr().cast(), buf.len(), 0) };
+  if n <= 0 {
+    return PressureReading::None;
+  }
+  let text = &buf[..n as usize];
+
+  let mut events = None;
+  for line in text.split(|&b| b == b'\n') {
+    let mut words = line.split(|&b| b == b' ').filter(|w| !w.is_empty());
+    match words.next() {
+      Some(b"some") => {
+        let total = words.find_map(|w| w.strip_prefix(b"total=")).map(parse_u64);
+        if let Some(total) = total {
+          return PressureReading::Stall(total);
+        }
+      }
+      Some(b"high" | b"max" | b"oom" | b"oom_kill") => {
+        let value = words.next().map(parse_u64).unwrap_or(0);
+        events = Some(events.unwrap_or(0) + value);
+      }
+      _ => {}
+    }
+  }
+  events.map_or(PressureReading::None, PressureReading::Events)
+}
+
+/// Release everything cached: shared caches now, thread caches on each thread's next slow path.
+#[cfg(feature = "pressure")]
+fn on_memory_pressure() {
+  PRESSURE_EVENTS.fetch_add(1, Ordering::Relaxed);
+  PURGE_EPOCH.fetch_add(1, Ordering::Relaxed);
+  for arena in arenas() {
+    arena.purge(u64::MAX, usize::MAX);
+    arena.coalesce();
+  }
+  huge_cache_purge(u64::MAX);
+}
+
+// =============================================================================
+// TLS
+// =============================================================================
+
+thread_local! {
+  static HEAP: UnsafeCell<ThreadHeap> = UnsafeCell::new(ThreadHeap::new());
+  static IN_ALLOC: Cell<bool> = const { Cell::new(false) };
+}
+
+fn with_heap<R: Default, F: FnOnce(&mut ThreadHeap, &Arena) -> R>(f: F) -> R {
+  // For dynamic linking (LD_PRELOAD), TLS may be destroyed during exit.
+  // We use try_with to avoid panicking when TLS is being destroyed.
+  #[cfg(feature = "dynamic")]
+  {
+    // Try to access IN_ALLOC; if TLS is destroyed, return default
+    let Ok(in_alloc) = IN_ALLOC.try_with(|flag| flag.get()) else {
+      return R::default();
+    };
+
+    if in_alloc {
+      return R::default();
+    }
+
+    // Set re-entrancy guard
+    let _ = IN_ALLOC.try_with(|flag| flag.set(true));
+
+    let result = HEAP
+      .try_with(|h| {
+        let heap = unsafe { &mut *h.get() };
+        Arena::for_node(heap.node)
+          .map(|a| f(heap, a))
+          .unwrap_or_default()
+      })
+      .unwrap_or_default();
+
+    let _ = IN_ALLOC.try_with(|flag| flag.set(false));
+    result
+  }
+
+  #[cfg(not(feature = "dynamic"))]
+  {
+    IN_ALLOC.with(|flag| {
+      if flag.get() {
+        return R::default();
+      }
+      flag.set(true);
+
+      let result = HEAP.with(|h| {
+        let heap = unsafe { &mut *h.get() };
+        Arena::for_node(heap.node)
+          .map(|a| f(heap, a))
+          .unwrap_or_default()
+      });
+
+      flag.set(false);
+      result
+    })
+  }
+}
+
+// =============================================================================
+// Small allocation / free
+// =============================================================================
+
+// NOTE: Do NOT reset `used` here. In-flight frees may still be pending.
+// Callers must verify used==0 before calling init_span.
+unsafe fn init_span(span: *mut SpanHeader, class: usize, tid: u32) {
+  let block_size = class_to_size(class);
+  let order = class_order(class);
+  // Every block is aligned to the largest power of two dividing its size. For every class this
+  // leaves as many blocks as starting right after the header.
+  let first = align_up(SPAN_HEADER_SIZE, block_size & block_size.wrapping_neg());
+  let capacity = ((SPAN_SIZE << order) - first) / block_size;
+  let base = unsafe { (span as *mut u8).add(first) };
+  let header = unsafe { &mut *span };
+  header.bump = base;
+  header.bump_end = unsafe { base.add(capacity * block_size) };
+  header.hot_block = null_mut();
+  header.local_free = null_mut();
+  header.remote_free.store(null_mut(), Ordering::Relaxed);
+  header.owner.store(tid, Ordering::Release);
+  header.in_reuse.store(false, Ordering::Relaxed);
+  header.block_size = block_size as u32;
+  header.class = class as u8;
+  header.kind = SpanKind::Small;
+  header.order = order as u8;
+  header.committed = true;
+  header.cache_next = null_mut();
+  header.huge_base = null_mut();
+  header.huge_size = 0;
+  header.magic = SPAN_MAGIC;
+}
+
+fn alloc_small(heap: &mut ThreadHeap, arena: &Arena, class: usize) -> Option<NonNull<u8>> {
+  loop {
+    let mut span = heap.spans[class];
+    if span.is_null() {
+      span = arena.get_span_small(heap, class);
+      if span.is_null() {
+        return None;
+      }
+      heap.spans[class] = span;
+    }
+
+    // Verify we own this span before using it
+    #[cfg(debug_assertions)]
+    {
+      let owner = unsafe { (*span).owner.load(Ordering::Relaxed) };
+      debug_assert!(
+        owner == heap.tid,
+        "alloc_small: span {:p} owner {} != our tid {}! class={}",
+        span,
+        owner,
+        heap.tid,
+        unsafe { (*span).class }
+      );
+    }
+
+    unsafe {
+      // Fast path: hot block (MRU)
+      let hot = (*span).hot_block;
+      if !hot.is_null() {
+        (*span).hot_block = null_mut();
+        (*span).used.fetch_add(1, Ordering::Relaxed);
+        return NonNull::new(hot);
+      }
+
       // Local free list
       let block = (*span).local_free;
       if !block.is_null() {
@@ -1115,57 +3314,184 @@ fn free_small(arena: &Arena, ptr: *mut u8, span: *mut SpanHeader) {
 
     if prev == 1 {
       core::sync::atomic::fence(Ordering::Acquire);
-      let owner = (*span).owner.load(Ordering::Acquire);
-      if owner == SPAN_OWNER_ORPHAN {
-        // This prevents double-enqueue and ensures only one thread handles cleanup.
-        if !(*span).in_reuse.swap(true, Ordering::AcqRel) {
-          // Re-verify owner after acquiring lock. If changed, we raced.
-          if (*span).owner.load(Ordering::Acquire) != SPAN_OWNER_ORPHAN {
-            // Span was claimed. Restore and abort.
-            (*span).in_reuse.store(false, Ordering::Release);
-            return;
-          }
+      arena.release_orphan(span);
+    }
+  }
+}
 
-          let class = (*span).class as usize;
-          let cpu = cpu_id();
-          // Try reuse cache first or fallback to global cache.
-          if !arena.reuse.push(cpu & (SHARD_COUNT - 1), class, span) {
-            arena.global_push(cpu, class, span);
-          }
+// =============================================================================
+// Huge Cache (freed huge mappings kept for reuse)
+// =============================================================================
+
+// Huge mappings are rounded up to size classes: exact up to 3 pages, then 4 per doubling
+// (at most 25% rounding), so a freed mapping fits every later request of its class.
+const HUGE_CLASSES: usize = 4 * (ADDRESS_BITS - PAGE_SIZE.trailing_zeros() as usize);
+
+/// One list per class, linked through the header's `cache_next`, newest first. Locked rather
+/// than lock-free: a popping thread could otherwise read the header of a mapping a purge just
+/// unmapped.
+static HUGE_CACHE: [AtomicPtr<SpanHeader>; HUGE_CLASSES] =
+  [const { AtomicPtr::new(null_mut()) }; HUGE_CLASSES];
+static HUGE_CACHE_LOCK: SpinLock = SpinLock::new();
+/// Bytes of the mappings in `HUGE_CACHE`.
+static HUGE_CACHED_BYTES: AtomicUsize = AtomicUsize::new(0);
+/// Bound on `HUGE_CACHED_BYTES` (`huge_cache` option, 0 disables the cache).
+static HUGE_CACHE_MAX: AtomicUsize = AtomicUsize::new(HUGE_CACHE_LIMIT);
+static HUGE_CACHE_HITS: AtomicUsize = AtomicUsize::new(0);
+static HUGE_CACHE_MISSES: AtomicUsize = AtomicUsize::new(0);
+
+/// Largest class no bigger than `pages`.
+#[inline]
+fn huge_class(pages: usize) -> usize {
+  if pages < 4 {
+    return pages;
+  }
+  let exp = (usize::BITS - 1 - pages.leading_zeros()) as usize;
+  4 * (exp - 1) + ((pages >> (exp - 2)) & 3)
+}
+
+/// Pages of a mapping of class `class`.
+#[inline]
+fn huge_class_pages(class: usize) -> usize {
+  if class < 4 {
+    return class;
+  }
+  (4 + class % 4) << (class / 4 - 1)
+}
+
+/// Pages to map for a huge block of `bytes`: its class, rounded up.
+fn huge_round_pages(bytes: usize) -> usize {
+  let pages = bytes.div_ceil(PAGE_SIZE);
+  let class = huge_class(pages);
+  huge_class_pages(class + (huge_class_pages(class) < pages) as usize)
+}
+
+/// Take a cached mapping for a block of `pages` (as returned by `huge_round_pages`).
+fn huge_cache_pop(pages: usize) -> *mut SpanHeader {
+  let head = &HUGE_CACHE[huge_class(pages)];
+  let mut span = null_mut();
+  if !head.load(Ordering::Relaxed).is_null() {
+    HUGE_CACHE_LOCK.lock();
+    span = head.load(Ordering::Relaxed);
+    if !span.is_null() {
+      head.store(unsafe { (*span).cache_next }, Ordering::Relaxed);
+    }
+    HUGE_CACHE_LOCK.unlock();
+  }
+  if span.is_null() {
+    HUGE_CACHE_MISSES.fetch_add(1, Ordering::Relaxed);
+  } else {
+    HUGE_CACHE_HITS.fetch_add(1, Ordering::Relaxed);
+    HUGE_CACHED_BYTES.fetch_sub(unsafe { (*span).huge_size }, Ordering::Relaxed);
+  }
+  span
+}
+
+/// Cache a freed huge mapping (already cleared from the page map). False if it would exceed
+/// the bound, in which case the caller unmaps it.
+fn huge_cache_push(span: *mut SpanHeader) -> bool {
+  let size = unsafe { (*span).huge_size };
+  let max = HUGE_CACHE_MAX.load(Ordering::Relaxed);
+  if HUGE_CACHED_BYTES.fetch_add(size, Ordering::Relaxed) + size > max {
+    HUGE_CACHED_BYTES.fetch_sub(size, Ordering::Relaxed);
+    return false;
+  }
+  let head = &HUGE_CACHE[huge_class(size / PAGE_SIZE)];
+  HUGE_CACHE_LOCK.lock();
+  unsafe {
+    (*span).last_used = now_ms();
+    (*span).cache_next = head.load(Ordering::Relaxed);
+  }
+  head.store(span, Ordering::Relaxed);
+  HUGE_CACHE_LOCK.unlock();
+  true
+}
+
+/// Unmap cached mappings idle since `cutoff` (ms) or earlier. Returns the bytes released.
+fn huge_cache_purge(cutoff: u64) -> usize {
+  if HUGE_CACHED_BYTES.load(Ordering::Relaxed) == 0 {
+    return 0;
+  }
+
+  // Unlink idle mappings under the lock, unmap them after.
+  let mut idle: *mut SpanHeader = null_mut();
+  HUGE_CACHE_LOCK.lock();
+  for head in &HUGE_CACHE {
+    let mut link = head.as_ptr();
+    unsafe {
+      while !(*link).is_null() {
+        let span = *link;
+        if (*span).last_used <= cutoff {
+          *link = (*span).cache_next;
+          (*span).cache_next = idle;
+          idle = span;
+        } else {
+          link = &raw mut (*span).cache_next;
         }
       }
-      // If owner != ORPHAN, the owning thread will handle via retire_small_span
     }
   }
+  HUGE_CACHE_LOCK.unlock();
+
+  let mut released = 0;
+  while !idle.is_null() {
+    let (base, size, next) = unsafe { ((*idle).huge_base, (*idle).huge_size, (*idle).cache_next) };
+    HUGE_CACHED_BYTES.fetch_sub(size, Ordering::Relaxed);
+    HUGE_MAPPED_BYTES.fetch_sub(size, Ordering::Relaxed);
+    HUGE_MAPPINGS.fetch_sub(1, Ordering::Relaxed);
+    unsafe { os_munmap(base, size) };
+    released += size;
+    idle = next;
+  }
+  released
 }
 
 // =============================================================================
 // Large / Huge allocation
 // =============================================================================
 
-fn alloc_large(arena: &Arena, size: usize) -> *mut u8 {
-  let total = match size.checked_add(SPAN_HEADER_SIZE) {
+/// Distance from the header of a Large or huge block aligned to `align` to its payload. The
+/// header sits on a span boundary, so up to a span this lands the payload on `align`. Above, the
+/// header must sit one span before an `align` boundary.
+#[inline]
+fn aligned_offset(align: usize) -> usize {
+  align.clamp(SPAN_HEADER_SIZE, SPAN_SIZE)
+}
+
+fn alloc_large(arena: &Arena, size: usize, align: usize) -> *mut u8 {
+  let offset = aligned_offset(align);
+  let total = match size.checked_add(offset) {
     Some(v) => v,
     None => return null_mut(),
   };
 
+  // Spans to trim around a run whose header must sit one span before an `align` boundary.
+  let boundary = (align / SPAN_SIZE).max(1);
   let spans = total.div_ceil(SPAN_SIZE);
-  let order = spans.next_power_of_two().trailing_zeros() as usize;
-
-  if order > BUDDY_MAX_ORDER {
-    return alloc_huge(size);
+  if spans + boundary - 1 > SPANS_PER_SEGMENT {
+    return alloc_huge(size, align);
   }
 
-  let Some(idx) = arena.buddy.alloc(arena, order) else {
-    return alloc_huge(size);
+  let Some(run) = arena.buddy_alloc_run(spans + boundary - 1) else {
+    return alloc_huge(size, align);
   };
+  let idx = align_up(run + 1, boundary) - 1;
+  if boundary > 1 {
+    let committed = unsafe { (*arena.idx_to_span(run)).committed };
+    arena.buddy_free_range(run, idx, committed);
+    arena.buddy_free_range(idx + spans, run + spans + boundary - 1, committed);
+    let span = arena.idx_to_span(idx);
+    page_map_set(span as usize, spans * SPAN_SIZE, span);
+  }
 
   let span = arena.idx_to_span(idx);
 
   unsafe {
     (*span).kind = SpanKind::Large;
-    (*span).order = order as u8;
+    (*span).spans = spans as u32;
+    (*span).offset = offset as u32;
     (*span).class = 255;
+    (*span).committed = true;
 
     (*span).owner.store(SPAN_OWNER_ORPHAN, Ordering::Relaxed);
     (*span).in_reuse.store(false, Ordering::Relaxed);
@@ -1174,272 +3500,1161 @@ fn alloc_large(arena: &Arena, size: usize) -> *mut u8 {
     (*span).cache_next = null_mut();
     (*span).magic = SPAN_MAGIC;
 
-    (span as *mut u8).add(SPAN_HEADER_SIZE)
+    (span as *mut u8).add(offset)
   }
 }
 
-fn alloc_huge(size: usize) -> *mut u8 {
-  let total = match size
-    .checked_add(SPAN_HEADER_SIZE)
-    .and_then(|v| v.checked_add(64))
-  {
-    Some(v) => v,
-    None => return null_mut(),
+/// Page-map range of a huge block: the spans from its header to its payload, the only ones
+/// `block_span` resolves. The rest of the mapping stays out of the page map, so its cost does
+/// not grow with the block.
+#[inline]
+fn huge_head(span: *mut SpanHeader) -> (usize, usize) {
+  let start = span as usize & !(SPAN_SIZE - 1);
+  (start, span as usize + payload_offset(span) + 1 - start)
+}
+
+/// Unmap a huge mapping that is in neither the page map nor the huge cache.
+unsafe fn huge_unmap(base: *mut u8, size: usize) {
+  unsafe { os_munmap(base, size) };
+  HUGE_MAPPED_BYTES.fetch_sub(size, Ordering::Relaxed);
+  HUGE_MAPPINGS.fetch_sub(1, Ordering::Relaxed);
+}
+
+fn alloc_huge(size: usize, align: usize) -> *mut u8 {
+  // Also keeps the class within `HUGE_CLASSES`.
+  if size >= 1 << ADDRESS_BITS || align >= 1 << ADDRESS_BITS {
+    return null_mut();
+  }
+  let offset = aligned_offset(align);
+  let pages = huge_round_pages(size + offset);
+  let total = pages * PAGE_SIZE;
+
+  // Cached mappings are only span-aligned.
+  let span = if align <= SPAN_SIZE {
+    huge_cache_pop(pages)
+  } else {
+    null_mut()
   };
+  if !span.is_null() {
+    unsafe {
+      (*span).offset = offset as u32;
+      let (start, len) = huge_head(span);
+      if page_map_reserve(start, len) {
+        page_map_set(start, len, span);
+        return (span as *mut u8).add(offset);
+      }
+      huge_unmap((*span).huge_base, (*span).huge_size);
+      return null_mut();
+    }
+  }
 
-  let raw = unsafe { os_mmap(total) };
-  if raw.is_null() {
+  // Apply `INICTUS_OPTIONS` (`huge_cache`) before the first mapping can be cached.
+  config();
+  if !budget_admit(total) {
     return null_mut();
   }
 
-  // Place header so that returned pointer is 64-aligned.
-  let header_addr = align_up(raw as usize + SPAN_HEADER_SIZE, 64) - SPAN_HEADER_SIZE;
-  let span = header_addr as *mut SpanHeader;
+  // Span-aligned so the mapping owns its page map entries outright.
+  let raw = unsafe {
+    if align <= SPAN_SIZE {
+      os_mmap_aligned(total, SPAN_SIZE, 0)
+    } else {
+      os_mmap_aligned(total, align, offset)
+    }
+  };
+  if raw.is_null() {
+    return null_mut();
+  }
+  if !page_map_reserve(raw as usize, offset + 1) {
+    unsafe { os_munmap(raw, total) };
+    return null_mut();
+  }
+  HUGE_MAPPED_BYTES.fetch_add(total, Ordering::Relaxed);
+  HUGE_MAPPINGS.fetch_add(1, Ordering::Relaxed);
 
+  let span = raw as *mut SpanHeader;
   unsafe {
     (*span).kind = SpanKind::Huge;
     (*span).huge_base = raw;
     (*span).huge_size = total;
+    (*span).offset = offset as u32;
 
     (*span).owner.store(SPAN_OWNER_ORPHAN, Ordering::Relaxed);
     (*span).in_reuse.store(false, Ordering::Relaxed);
     (*span).used.store(0, Ordering::Relaxed);
     (*span).remote_free.store(null_mut(), Ordering::Relaxed);
     (*span).magic = SPAN_MAGIC;
-
-    (span as *mut u8).add(SPAN_HEADER_SIZE)
   }
+  let (start, len) = huge_head(span);
+  page_map_set(start, len, span);
+
+  unsafe { raw.add(offset) }
 }
 
 fn free_large(arena: &Arena, span: *mut SpanHeader) {
-  let order = unsafe { (*span).order as usize };
-  arena.buddy.free(arena, arena.span_to_idx(span), order);
+  let idx = arena.span_to_idx(span);
+  let spans = unsafe { (*span).spans as usize };
+  arena.buddy_free_range(idx, idx + spans, true);
 }
 
 fn free_huge(span: *mut SpanHeader) {
   unsafe {
-    if !(*span).huge_base.is_null() && (*span).huge_size != 0 {
-      os_munmap((*span).huge_base, (*span).huge_size);
+    let (base, size) = ((*span).huge_base, (*span).huge_size);
+    if !base.is_null() && size != 0 {
+      let (start, len) = huge_head(span);
+      page_map_set(start, len, null_mut());
+      if !huge_cache_push(span) {
+        huge_unmap(base, size);
+      }
+    }
+  }
+}
+
+/// Resize the block at `ptr` without copying when it stays in its tier: Large blocks through
+/// their buddies, huge blocks with `mremap`. Null if the caller must allocate and copy.
+fn realloc_in_place(ptr: *mut u8, size: usize, align: usize) -> *mut u8 {
+  let Some(span) = block_span(ptr) else {
+    return null_mut();
+  };
+  match unsafe { (*span).kind } {
+    SpanKind::Large if size > MEDIUM_MAX_SIZE && size <= SEGMENT_SIZE / 2 => {
+      Arena::owner(ptr).map_or(null_mut(), |arena| realloc_large(arena, span, size))
+    }
+    // Moved mappings are only span-aligned.
+    SpanKind::Huge
+      if size > HUGE_THRESHOLD.load(Ordering::Relaxed)
+        && size < 1 << ADDRESS_BITS
+        && align <= SPAN_SIZE =>
+    {
+      realloc_huge(span, size)
     }
+    _ => null_mut(),
+  }
+}
+
+/// Resize a Large run: a shrink frees its tail spans, growth takes the free spans after it.
+/// Null if they are not free.
+fn realloc_large(arena: &Arena, span: *mut SpanHeader, size: usize) -> *mut u8 {
+  let idx = arena.span_to_idx(span);
+  let spans = unsafe { (*span).spans as usize };
+  let new_spans = (size + payload_offset(span)).div_ceil(SPAN_SIZE);
+  if new_spans < spans {
+    // The tail spans held user data: they are resident.
+    arena.buddy_free_range(idx + new_spans, idx + spans, true);
+  } else if new_spans > spans && !arena.buddy_grow(idx, spans, new_spans) {
+    return null_mut();
+  }
+  unsafe {
+    (*span).spans = new_spans as u32;
+    (span as *mut u8).add(payload_offset(span))
+  }
+}
+
+/// Resize a huge block with `mremap`: shrinks return the tail pages, growth extends the mapping
+/// in place or moves its pages to a fresh span-aligned range (no copy).
+#[cfg(target_os = "linux")]
+fn realloc_huge(span: *mut SpanHeader, size: usize) -> *mut u8 {
+  let offset = payload_offset(span);
+  let ptr = unsafe { (span as *mut u8).add(offset) };
+  let (base, old) = unsafe { ((*span).huge_base, (*span).huge_size) };
+  let (head, head_len) = huge_head(span);
+  let new = huge_round_pages(size + offset) * PAGE_SIZE;
+  if new == old {
+    return ptr;
+  }
+
+  if new < old {
+    let shrunk = unsafe { libc::mremap(base.cast(), old, new, 0) };
+    if shrunk == libc::MAP_FAILED {
+      return ptr; // Still valid, just bigger than needed.
+    }
+    // The head spans, the only ones in the page map, are kept.
+    HUGE_MAPPED_BYTES.fetch_sub(old - new, Ordering::Relaxed);
+    unsafe { (*span).huge_size = new };
+    return ptr;
+  }
+
+  if !budget_admit(new - old) {
+    return null_mut();
+  }
+  let mut moved = unsafe { libc::mremap(base.cast(), old, new, 0) };
+  if moved == libc::MAP_FAILED {
+    // No room after the mapping: move it over a span-aligned reservation, which the kernel
+    // replaces atomically.
+    let dst = unsafe { os_mmap_aligned(new, SPAN_SIZE, 0) };
+    if dst.is_null() {
+      return null_mut();
+    }
+    if page_map_reserve(dst as usize, offset + 1) {
+      let flags = libc::MREMAP_MAYMOVE | libc::MREMAP_FIXED;
+      moved = unsafe { libc::mremap(base.cast(), old, new, flags, dst) };
+    }
+    if moved == libc::MAP_FAILED {
+      unsafe { os_munmap(dst, new) };
+      return null_mut();
+    }
+    page_map_set(head, head_len, null_mut());
+  }
+  HUGE_MAPPED_BYTES.fetch_add(new - old, Ordering::Relaxed);
+
+  let span = moved as *mut SpanHeader;
+  unsafe {
+    (*span).huge_base = moved.cast();
+    (*span).huge_size = new;
+  }
+  let (start, len) = huge_head(span);
+  page_map_set(start, len, span);
+  unsafe { (span as *mut u8).add(offset) }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn realloc_huge(_span: *mut SpanHeader, _size: usize) -> *mut u8 {
+  null_mut()
+}
+
+// =============================================================================
+// GlobalAlloc
+// =============================================================================
+
+pub struct Allocator;
+
+unsafe impl GlobalAlloc for Allocator {
+  unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+    let size = layout.size().max(1);
+
+    // High alignment: a class whose blocks are naturally aligned, otherwise an aligned run.
+    if layout.align() > 16 {
+      if let Some(class) = size_to_class_aligned(size, layout.align())
+        && let Some(p) = with_heap(|heap, arena| alloc_small(heap, arena, class))
+      {
+        return p.as_ptr();
+      }
+    } else if size <= MEDIUM_MAX_SIZE
+      && let Some(p) = with_heap(|heap, arena| alloc_small(heap, arena, size_to_class(size)))
+    {
+      return p.as_ptr();
+    }
+
+    Arena::get()
+      .map(|a| {
+        if size <= HUGE_THRESHOLD.load(Ordering::Relaxed) {
+          alloc_large(a, size, layout.align())
+        } else {
+          alloc_huge(size, layout.align())
+        }
+      })
+      .unwrap_or(null_mut())
+  }
+
+  unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
+    if ptr.is_null() {
+      return;
+    }
+
+    // Foreign pointers (and pointers inside a Large or huge block) are ignored.
+    let Some(span) = block_span(ptr) else {
+      return;
+    };
+    match unsafe { (*span).kind } {
+      SpanKind::Small => {
+        if let Some(arena) = Arena::owner(ptr) {
+          free_small(arena, ptr, span)
+        }
+      }
+      SpanKind::Large => {
+        if let Some(arena) = Arena::owner(ptr) {
+          free_large(arena, span)
+        }
+      }
+      SpanKind::Huge => free_huge(span),
+    }
+  }
+
+  unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+    if ptr.is_null() {
+      return unsafe {
+        self.alloc(Layout::from_size_align_unchecked(
+          new_size.max(1),
+          layout.align(),
+        ))
+      };
+    }
+
+    if new_size == 0 {
+      unsafe { self.dealloc(ptr, layout) };
+      return null_mut();
+    }
+
+    // Same size class optimization (small and medium only)
+    let old_size = layout.size();
+    if old_size <= MEDIUM_MAX_SIZE
+      && new_size <= MEDIUM_MAX_SIZE
+      && size_to_class(old_size) == size_to_class(new_size)
+    {
+      return ptr;
+    }
+
+    let resized = realloc_in_place(ptr, new_size, layout.align());
+    if !resized.is_null() {
+      return resized;
+    }
+
+    let new_ptr =
+      unsafe { self.alloc(Layout::from_size_align_unchecked(new_size, layout.align())) };
+
+    if !new_ptr.is_null() {
+      unsafe { ptr::copy_nonoverlapping(ptr, new_ptr, old_size.min(new_size)) };
+      unsafe { self.dealloc(ptr, layout) };
+    }
+
+    new_ptr
+  }
+
+  unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+    let ptr = unsafe { self.alloc(layout) };
+    if !ptr.is_null() {
+      unsafe { ptr::write_bytes(ptr, 0, layout.size()) }
+    }
+    ptr
+  }
+}
+
+// =============================================================================
+// C API (enabled with --features c_api)
+// =============================================================================
+
+/// Sets the calling thread's `errno`.
+#[cfg(feature = "c_api")]
+fn set_errno(code: i32) {
+  #[cfg(target_os = "linux")]
+  let errno = unsafe { libc::__errno_location() };
+  #[cfg(not(target_os = "linux"))]
+  let errno = unsafe { libc::__error() };
+  unsafe { *errno = code }
+}
+
+/// Allocates `size` bytes aligned to `alignment` (a power of two), setting `ENOMEM` on failure.
+#[cfg(feature = "c_api")]
+fn alloc_aligned(alignment: usize, size: usize) -> *mut u8 {
+  static A: Allocator = Allocator;
+  let ptr = match Layout::from_size_align(size.max(1), alignment) {
+    Ok(layout) => unsafe { A.alloc(layout) },
+    Err(_) => null_mut(),
+  };
+  if ptr.is_null() {
+    set_errno(libc::ENOMEM);
+  }
+  ptr
+}
+
+#[cfg(feature = "c_api")]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn malloc(size: usize) -> *mut u8 {
+  alloc_aligned(16, size)
+}
+
+#[cfg(feature = "c_api")]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn free(ptr: *mut u8) {
+  if ptr.is_null() {
+    return;
+  }
+  static A: Allocator = Allocator;
+  unsafe { A.dealloc(ptr, Layout::from_size_align_unchecked(1, 1)) }
+}
+
+#[cfg(feature = "c_api")]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn calloc(nmemb: usize, size: usize) -> *mut u8 {
+  let Some(total) = nmemb.checked_mul(size) else {
+    set_errno(libc::ENOMEM);
+    return null_mut();
+  };
+  if total == 0 {
+    return null_mut();
+  }
+  static A: Allocator = Allocator;
+  let ptr = match Layout::from_size_align(total, 8) {
+    Ok(layout) => unsafe { A.alloc_zeroed(layout) },
+    Err(_) => null_mut(),
+  };
+  if ptr.is_null() {
+    set_errno(libc::ENOMEM);
+  }
+  ptr
+}
+
+#[cfg(feature = "c_api")]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn realloc(ptr: *mut u8, size: usize) -> *mut u8 {
+  static A: Allocator = Allocator;
+
+  if ptr.is_null() {
+    return alloc_aligned(8, size);
+  }
+
+  if size == 0 {
+    unsafe { A.dealloc(ptr, Layout::from_size_align_unchecked(1, 1)) };
+    return null_mut();
+  }
+
+  // The old size comes from the span, so a foreign pointer cannot be moved.
+  let Some(span) = block_span(ptr) else {
+    set_errno(libc::ENOMEM);
+    return null_mut();
+  };
+  let old_size = usable_size(span);
+  if unsafe { (*span).kind } == SpanKind::Small && size <= old_size {
+    return ptr;
+  }
+
+  // Large runs drop their tail spans and huge blocks shrink with `mremap`. Below their tier
+  // they are copied into a smaller block, so the old memory is returned.
+  let resized = realloc_in_place(ptr, size, 8);
+  if !resized.is_null() {
+    return resized;
+  }
+
+  let new_ptr = alloc_aligned(8, size);
+
+  if !new_ptr.is_null() {
+    unsafe { ptr::copy_nonoverlapping(ptr, new_ptr, old_size.min(size)) };
+    unsafe { A.dealloc(ptr, Layout::from_size_align_unchecked(1, 1)) };
+  }
+
+  new_ptr
+}
+
+/// `realloc` of `nmemb * size` bytes. On overflow it fails with `ENOMEM` and leaves `ptr`
+/// untouched.
+#[cfg(feature = "c_api")]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn reallocarray(ptr: *mut u8, nmemb: usize, size: usize) -> *mut u8 {
+  let Some(total) = nmemb.checked_mul(size) else {
+    set_errno(libc::ENOMEM);
+    return null_mut();
+  };
+  unsafe { realloc(ptr, total) }
+}
+
+#[cfg(feature = "c_api")]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn posix_memalign(
+  memptr: *mut *mut u8,
+  alignment: usize,
+  size: usize,
+) -> i32 {
+  if !alignment.is_power_of_two() || alignment < core::mem::size_of::<*mut u8>() {
+    return libc::EINVAL;
+  }
+
+  // posix_memalign reports errors through its result and leaves errno alone.
+  static A: Allocator = Allocator;
+  let ptr = match Layout::from_size_align(size.max(1), alignment) {
+    Ok(layout) => unsafe { A.alloc(layout) },
+    Err(_) => null_mut(),
+  };
+
+  if ptr.is_null() {
+    return libc::ENOMEM;
+  }
+
+  unsafe { *memptr = ptr };
+  0
+}
+
+/// C11 `aligned_alloc`. `alignment` must be a power of two, otherwise it fails with `EINVAL`.
+#[cfg(feature = "c_api")]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn aligned_alloc(alignment: usize, size: usize) -> *mut u8 {
+  if !alignment.is_power_of_two() {
+    set_errno(libc::EINVAL);
+    return null_mut();
+  }
+  alloc_aligned(alignment, size)
+}
+
+/// Legacy `memalign`. Like glibc, an `alignment` that is not a power of two is rounded up to
+/// one, and only an alignment too large to round fails with `EINVAL`.
+#[cfg(feature = "c_api")]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn memalign(alignment: usize, size: usize) -> *mut u8 {
+  let Some(alignment) = alignment.checked_next_power_of_two() else {
+    set_errno(libc::EINVAL);
+    return null_mut();
+  };
+  alloc_aligned(alignment, size)
+}
+
+/// Legacy `valloc`: page-aligned `malloc`.
+#[cfg(feature = "c_api")]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn valloc(size: usize) -> *mut u8 {
+  alloc_aligned(PAGE_SIZE, size)
+}
+
+/// Legacy `pvalloc`: page-aligned `malloc` of `size` rounded up to whole pages (one page for 0).
+#[cfg(feature = "c_api")]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pvalloc(size: usize) -> *mut u8 {
+  let Some(size) = size.max(1).checked_next_multiple_of(PAGE_SIZE) else {
+    set_errno(libc::ENOMEM);
+    return null_mut();
+  };
+  alloc_aligned(PAGE_SIZE, size)
+}
+
+/// C23 `free_sized`. Blocks are found through the page map, so `size` is not needed.
+#[cfg(feature = "c_api")]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn free_sized(ptr: *mut u8, _size: usize) {
+  unsafe { free(ptr) }
+}
+
+/// C23 `free_aligned_sized`. As with `free_sized`, `alignment` and `size` are not needed.
+#[cfg(feature = "c_api")]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn free_aligned_sized(ptr: *mut u8, _alignment: usize, _size: usize) {
+  unsafe { free(ptr) }
+}
+
+#[cfg(feature = "c_api")]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn malloc_usable_size(ptr: *mut u8) -> usize {
+  if ptr.is_null() {
+    return 0;
+  }
+
+  // Foreign pointer: conservative fallback
+  block_span(ptr).map_or(0, usable_size)
+}
+
+/// Usable size of the block `span` holds (any block of a small span).
+#[cfg(feature = "c_api")]
+fn usable_size(span: *mut SpanHeader) -> usize {
+  match unsafe { (*span).kind } {
+    SpanKind::Small => unsafe { (*span).block_size as usize },
+    SpanKind::Large => unsafe { (*span).spans as usize * SPAN_SIZE - payload_offset(span) },
+    SpanKind::Huge => unsafe { (*span).huge_size - payload_offset(span) },
+  }
+}
+
+/// glibc `malloc_trim`. Returns 1 if memory was released. There is no heap top in inictus, so
+/// `pad` is ignored.
+#[cfg(feature = "c_api")]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn malloc_trim(_pad: usize) -> i32 {
+  (trim() > 0) as i32
+}
+
+/// glibc `struct mallinfo2`, filled from [`stats`]. The arena plays the part of glibc's main
+/// heap and huge mappings that of its mmapped chunks:
+///
+/// - `arena`: committed arena bytes, `uordblks + fordblks`.
+/// - `ordblks`: free buddy blocks whose pages are still committed.
+/// - `smblks`: fully free spans in the global cache and empty pool.
+/// - `hblks`, `hblkhd`: huge mappings and their bytes, including the huge cache.
+/// - `usmblks`: always 0, as in glibc.
+/// - `fsmblks`: bytes of the `smblks` spans.
+/// - `uordblks`: bytes of spans holding live blocks (in use or in a thread cache).
+/// - `fordblks`: `fsmblks` plus the committed free buddy blocks.
+/// - `keepcost`: bytes `malloc_trim` could release: `fordblks` plus the huge cache.
+#[cfg(feature = "c_api")]
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Mallinfo2 {
+  pub arena: usize,
+  pub ordblks: usize,
+  pub smblks: usize,
+  pub hblks: usize,
+  pub hblkhd: usize,
+  pub usmblks: usize,
+  pub fsmblks: usize,
+  pub uordblks: usize,
+  pub fordblks: usize,
+  pub keepcost: usize,
+}
+
+#[cfg(feature = "c_api")]
+impl Mallinfo2 {
+  fn new(stats: &Stats) -> Self {
+    let committed_blocks = (0..=BUDDY_MAX_ORDER)
+      .map(|o| stats.buddy_committed_bytes[o] / (SPAN_SIZE << o))
+      .sum();
+    let committed_bytes: usize = stats.buddy_committed_bytes.iter().sum();
+    let cached = stats.global_cached_bytes + stats.empty_cached_spans * SPAN_SIZE;
+    let spans = stats.active_spans * SPAN_SIZE;
+    Self {
+      arena: spans + committed_bytes,
+      ordblks: committed_blocks,
+      smblks: stats.global_cached_spans + stats.empty_cached_spans,
+      hblks: stats.huge_mappings,
+      hblkhd: stats.huge_mapped_bytes,
+      usmblks: 0,
+      fsmblks: cached,
+      uordblks: spans.saturating_sub(cached),
+      fordblks: cached + committed_bytes,
+      keepcost: cached + committed_bytes + stats.huge_cached_bytes,
+    }
+  }
+}
+
+/// glibc `mallinfo2`. See [`Mallinfo2`] for what each field counts.
+#[cfg(feature = "c_api")]
+#[unsafe(no_mangle)]
+pub extern "C" fn mallinfo2() -> Mallinfo2 {
+  Mallinfo2::new(&stats())
+}
+
+// glibc `mallopt` parameters.
+#[cfg(feature = "c_api")]
+const M_TRIM_THRESHOLD: i32 = -1;
+#[cfg(feature = "c_api")]
+const M_MMAP_THRESHOLD: i32 = -3;
+
+/// glibc `mallopt`. Returns 1 on success, 0 for a parameter without an inictus equivalent or a
+/// bad value.
+///
+/// - `M_TRIM_THRESHOLD`, the amount of freed memory glibc keeps before trimming, sets the huge
+///   cache bound (the `huge_cache` option).
+/// - `M_MMAP_THRESHOLD` sets the size above which allocations get a huge mapping, clamped to
+///   the sizes Large runs can serve.
+///
+/// `M_ARENA_MAX` has no equivalent: inictus has one arena per NUMA node, and the `shards`
+/// option, fixed on first use, splits its caches rather than capping them.
+#[cfg(feature = "c_api")]
+#[unsafe(no_mangle)]
+pub extern "C" fn mallopt(param: i32, value: i32) -> i32 {
+  if value < 0 {
+    return 0;
+  }
+  let value = value as usize;
+  match param {
+    M_TRIM_THRESHOLD => {
+      HUGE_CACHE_MAX.store(value, Ordering::Relaxed);
+      if HUGE_CACHED_BYTES.load(Ordering::Relaxed) > value {
+        huge_cache_purge(u64::MAX);
+      }
+      1
+    }
+    M_MMAP_THRESHOLD => {
+      let threshold = value.clamp(MEDIUM_MAX_SIZE, SEGMENT_SIZE / 2);
+      HUGE_THRESHOLD.store(threshold, Ordering::Relaxed);
+      1
+    }
+    _ => 0,
+  }
+}
+
+/// Fixed-size text buffer for the reports below, so they never allocate. Output past the end
+/// is dropped.
+#[cfg(feature = "c_api")]
+struct ReportBuf {
+  buf: [u8; 4096],
+  len: usize,
+}
+
+#[cfg(feature = "c_api")]
+impl ReportBuf {
+  fn new() -> Self {
+    Self {
+      buf: [0; 4096],
+      len: 0,
+    }
+  }
+
+  fn as_bytes(&self) -> &[u8] {
+    &self.buf[..self.len]
+  }
+}
+
+#[cfg(feature = "c_api")]
+impl core::fmt::Write for ReportBuf {
+  fn write_str(&mut self, s: &str) -> core::fmt::Result {
+    let end = self.len + s.len();
+    let dst = self.buf.get_mut(self.len..end).ok_or(core::fmt::Error)?;
+    dst.copy_from_slice(s.as_bytes());
+    self.len = end;
+    Ok(())
+  }
+}
+
+/// glibc `malloc_stats`: prints the [`mallinfo2`] totals to stderr in glibc's layout, then
+/// the inictus counters behind them.
+#[cfg(feature = "c_api")]
+#[unsafe(no_mangle)]
+pub extern "C" fn malloc_stats() {
+  use core::fmt::Write;
+
+  let stats = stats();
+  let info = Mallinfo2::new(&stats);
+  let mut out = ReportBuf::new();
+  let _ = write!(
+    out,
+    "Total (incl. mmap):\n\
+     system bytes     = {:>10}\n\
+     in use bytes     = {:>10}\n\
+     mmap regions     = {:>10}\n\
+     mmap bytes       = {:>10}\n\
+     segments         = {:>10}\n\
+     arenas           = {:>10}\n\
+     active spans     = {:>10}\n\
+     cached spans     = {:>10}\n\
+     reuse spans      = {:>10}\n\
+     orphan spans     = {:>10}\n\
+     huge cache bytes = {:>10}\n\
+     buddy free       = {:?}\n",
+    info.arena + info.hblkhd,
+    (info.uordblks + info.hblkhd).saturating_sub(stats.huge_cached_bytes),
+    info.hblks,
+    info.hblkhd,
+    stats.segments,
+    stats.arenas,
+    stats.active_spans,
+    info.smblks,
+    stats.reuse_cached_spans,
+    stats.orphan_spans,
+    stats.huge_cached_bytes,
+    stats.buddy_free_blocks,
+  );
+  let bytes = out.as_bytes();
+  unsafe { libc::write(2, bytes.as_ptr().cast(), bytes.len()) };
+}
+
+/// glibc `malloc_info`: writes the allocator state to `stream` as glibc-style XML. Free
+/// buddy blocks are listed by size in `<sizes>`, cached spans as the `fast` total, huge
+/// mappings as `mmap`. `options` must be 0, otherwise it fails with `EINVAL`.
+#[cfg(feature = "c_api")]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn malloc_info(options: i32, stream: *mut libc::FILE) -> i32 {
+  use core::fmt::Write;
+
+  if options != 0 {
+    set_errno(libc::EINVAL);
+    return -1;
+  }
+
+  let stats = stats();
+  let info = Mallinfo2::new(&stats);
+  let mut out = ReportBuf::new();
+  let _ = write!(out, "<malloc version=\"1\">\n<sizes>\n");
+  for order in 0..=BUDDY_MAX_ORDER {
+    let count = stats.buddy_free_blocks[order];
+    if count > 0 {
+      let size = SPAN_SIZE << order;
+      let _ = writeln!(
+        out,
+        "  <size from=\"{size}\" to=\"{size}\" total=\"{}\" count=\"{count}\"/>",
+        count * size
+      );
+    }
+  }
+  let _ = write!(
+    out,
+    "</sizes>\n\
+     <total type=\"fast\" count=\"{}\" size=\"{}\"/>\n\
+     <total type=\"rest\" count=\"{}\" size=\"{}\"/>\n\
+     <total type=\"mmap\" count=\"{}\" size=\"{}\"/>\n\
+     <system type=\"current\" size=\"{}\"/>\n\
+     <aspace type=\"total\" size=\"{}\"/>\n\
+     </malloc>\n",
+    info.smblks,
+    info.fsmblks,
+    info.ordblks,
+    info.fordblks - info.fsmblks,
+    info.hblks,
+    info.hblkhd,
+    info.arena + info.hblkhd,
+    stats.segments * SEGMENT_SIZE + info.hblkhd,
+  );
+  let bytes = out.as_bytes();
+  if unsafe { libc::fwrite(bytes.as_ptr().cast(), 1, bytes.len(), stream) } != bytes.len() {
+    return -1;
   }
+  0
 }
 
-// =============================================================================
-// GlobalAlloc
-// =============================================================================
+// glibc's internal entry points, which some libraries and tools call to reach the allocator
+// behind any wrapper.
 
-pub struct Allocator;
+#[cfg(feature = "c_api")]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn __libc_malloc(size: usize) -> *mut u8 {
+  unsafe { malloc(size) }
+}
 
-unsafe impl GlobalAlloc for Allocator {
-  unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-    let size = layout.size().max(1);
+#[cfg(feature = "c_api")]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn __libc_free(ptr: *mut u8) {
+  unsafe { free(ptr) }
+}
 
-    // Route high alignment to huge.
-    if layout.align() > 16 {
-      return alloc_huge(size);
-    }
+#[cfg(feature = "c_api")]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn __libc_calloc(nmemb: usize, size: usize) -> *mut u8 {
+  unsafe { calloc(nmemb, size) }
+}
 
-    if size <= CLASSES_MAX_SIZE
-      && let Some(p) = with_heap(|heap, arena| alloc_small(heap, arena, size))
-    {
-      return p.as_ptr();
-    }
+#[cfg(feature = "c_api")]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn __libc_realloc(ptr: *mut u8, size: usize) -> *mut u8 {
+  unsafe { realloc(ptr, size) }
+}
 
-    Arena::get()
-      .map(|a| {
-        if size <= ARENA_SIZE / 2 {
-          alloc_large(a, size)
-        } else {
-          alloc_huge(size)
-        }
-      })
-      .unwrap_or(null_mut())
-  }
+#[cfg(feature = "c_api")]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn __libc_memalign(alignment: usize, size: usize) -> *mut u8 {
+  unsafe { memalign(alignment, size) }
+}
 
-  unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
-    if ptr.is_null() {
-      return;
-    }
+pub unsafe fn ralloc_malloc(size: usize) -> *mut u8 {
+  static A: Allocator = Allocator;
+  unsafe { A.alloc(Layout::from_size_align_unchecked(size.max(1), 8)) }
+}
 
-    if let Some(arena) = ARENA.get() {
-      if arena.contains(ptr) {
-        let span = arena.ptr_to_span(ptr);
-        match unsafe { (*span).kind } {
-          SpanKind::Small => free_small(arena, ptr, span),
-          SpanKind::Large => free_large(arena, span),
-          SpanKind::Huge => free_huge(span),
-        }
-        return;
-      }
-    }
+pub unsafe fn ralloc_free(ptr: *mut u8) {
+  static A: Allocator = Allocator;
+  unsafe { A.dealloc(ptr, Layout::from_size_align_unchecked(1, 1)) }
+}
 
-    // Pointer is outside arena. Check if it's a huge allocation via magic number.
-    let span = (ptr as usize - SPAN_HEADER_SIZE) as *mut SpanHeader;
-    unsafe {
-      if (*span).magic == SPAN_MAGIC && (*span).kind == SpanKind::Huge {
-        free_huge(span);
-      }
-    }
+// =============================================================================
+// C++ API (enabled with --features c_api)
+// =============================================================================
+//
+// The Itanium `operator new`/`operator delete` family. A failed throwing `new` has to run the
+// installed `std::new_handler` and throw `std::bad_alloc`, and an exception cannot cross Rust
+// frames built with `panic = "abort"`. So throwing `new` is a naked trampoline: it tries the
+// allocation, and on failure tail-jumps to the C++ runtime's own `operator new` (found with
+// `RTLD_NEXT`), which retries through `malloc`, runs the handler and throws. Throwing `new` is
+// only exported on x86_64 and aarch64; elsewhere the runtime's `operator new` serves it through
+// `malloc`.
+
+/// Alignment of plain `new`, matching `__STDCPP_DEFAULT_NEW_ALIGNMENT__`.
+#[cfg(feature = "c_api")]
+const CXX_NEW_ALIGN: usize = 16;
+
+/// The throwing `new` operators, in the order the trampolines pass as `which`.
+#[cfg(all(
+  feature = "c_api",
+  any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+const CXX_NEW: [&core::ffi::CStr; 4] = [
+  c"_Znwm",
+  c"_Znam",
+  c"_ZnwmSt11align_val_t",
+  c"_ZnamSt11align_val_t",
+];
+
+/// Fast path of throwing `new` number `which`; `align` is only read by the aligned ones.
+#[cfg(all(
+  feature = "c_api",
+  any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+extern "C" fn cxx_new_try(size: usize, align: usize, which: usize) -> *mut u8 {
+  alloc_aligned(if which < 2 { CXX_NEW_ALIGN } else { align }, size)
+}
 
-    // It's a foreign pointer, is ignored.
+/// The C++ runtime's throwing `new` number `which`. Without one nothing can throw `bad_alloc`,
+/// so this aborts.
+#[cold]
+#[cfg(all(
+  feature = "c_api",
+  any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+extern "C" fn cxx_new_next(which: usize) -> *mut libc::c_void {
+  let next = unsafe { libc::dlsym(libc::RTLD_NEXT, CXX_NEW[which].as_ptr()) };
+  if next.is_null() {
+    std::process::abort();
   }
+  next
+}
 
-  unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
-    if ptr.is_null() {
-      return unsafe {
-        self.alloc(Layout::from_size_align_unchecked(
-          new_size.max(1),
-          layout.align(),
-        ))
-      };
+/// Defines throwing `new` number `which` as a trampoline into [`cxx_new_try`] and, when that
+/// fails, [`cxx_new_next`] with the original arguments restored.
+macro_rules! cxx_throwing_new {
+  ($(#[$attr:meta])* $name:ident($($arg:ident),+) = $which:literal) => {
+    $(#[$attr])*
+    #[cfg(all(feature = "c_api", target_arch = "x86_64"))]
+    #[unsafe(naked)]
+    pub unsafe extern "C" fn $name($($arg: usize),+) -> *mut u8 {
+      core::arch::naked_asm!(
+        "push rdi",
+        "push rsi",
+        "push rdx",
+        "mov edx, {which}",
+        "call {try}",
+        "test rax, rax",
+        "jz 2f",
+        "add rsp, 24",
+        "ret",
+        "2:",
+        "mov edi, {which}",
+        "call {next}",
+        "pop rdx",
+        "pop rsi",
+        "pop rdi",
+        "jmp rax",
+        which = const $which,
+        try = sym cxx_new_try,
+        next = sym cxx_new_next,
+      )
     }
 
-    if new_size == 0 {
-      unsafe { self.dealloc(ptr, layout) };
-      return null_mut();
+    $(#[$attr])*
+    #[cfg(all(feature = "c_api", target_arch = "aarch64"))]
+    #[unsafe(naked)]
+    pub unsafe extern "C" fn $name($($arg: usize),+) -> *mut u8 {
+      core::arch::naked_asm!(
+        "stp x0, x1, [sp, #-32]!",
+        "str x30, [sp, #16]",
+        "mov x2, #{which}",
+        "bl {try}",
+        "cbz x0, 2f",
+        "ldr x30, [sp, #16]",
+        "add sp, sp, #32",
+        "ret",
+        "2:",
+        "mov x0, #{which}",
+        "bl {next}",
+        "mov x16, x0",
+        "ldr x30, [sp, #16]",
+        "ldp x0, x1, [sp], #32",
+        "br x16",
+        which = const $which,
+        try = sym cxx_new_try,
+        next = sym cxx_new_next,
+      )
     }
+  };
+}
 
-    // Same size class optimization (small only)
-    let old_size = layout.size();
-    if old_size <= CLASSES_MAX_SIZE
-      && new_size <= CLASSES_MAX_SIZE
-      && size_to_class(old_size) == size_to_class(new_size)
-    {
-      return ptr;
-    }
+cxx_throwing_new! {
+  /// `operator new(size_t)`
+  #[unsafe(export_name = "_Znwm")]
+  cxx_new(size) = 0
+}
 
-    let new_ptr =
-      unsafe { self.alloc(Layout::from_size_align_unchecked(new_size, layout.align())) };
+cxx_throwing_new! {
+  /// `operator new[](size_t)`
+  #[unsafe(export_name = "_Znam")]
+  cxx_new_array(size) = 1
+}
 
-    if !new_ptr.is_null() {
-      unsafe { ptr::copy_nonoverlapping(ptr, new_ptr, old_size.min(new_size)) };
-      unsafe { self.dealloc(ptr, layout) };
-    }
+cxx_throwing_new! {
+  /// `operator new(size_t, std::align_val_t)`
+  #[unsafe(export_name = "_ZnwmSt11align_val_t")]
+  cxx_new_aligned(size, align) = 2
+}
 
-    new_ptr
-  }
+cxx_throwing_new! {
+  /// `operator new[](size_t, std::align_val_t)`
+  #[unsafe(export_name = "_ZnamSt11align_val_t")]
+  cxx_new_array_aligned(size, align) = 3
+}
 
-  unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
-    let ptr = unsafe { self.alloc(layout) };
-    if !ptr.is_null() {
-      unsafe { ptr::write_bytes(ptr, 0, layout.size()) }
+/// Slow path of nothrow `new`: the C++ runtime's nothrow `new` runs the handler and catches
+/// `bad_alloc` itself, so no exception reaches Rust. Null without a runtime.
+#[cold]
+#[cfg(feature = "c_api")]
+fn cxx_new_nothrow_next(
+  name: &core::ffi::CStr,
+  size: usize,
+  align: Option<usize>,
+  nothrow: *const u8,
+) -> *mut u8 {
+  let next = unsafe { libc::dlsym(libc::RTLD_NEXT, name.as_ptr()) };
+  if next.is_null() {
+    return null_mut();
+  }
+  unsafe {
+    if let Some(align) = align {
+      let next = core::mem::transmute::<*mut libc::c_void, NothrowNewAligned>(next);
+      next(size, align, nothrow)
+    } else {
+      let next = core::mem::transmute::<*mut libc::c_void, NothrowNew>(next);
+      next(size, nothrow)
     }
-    ptr
   }
 }
 
-// =============================================================================
-// C API (enabled with --features c_api)
-// =============================================================================
-
 #[cfg(feature = "c_api")]
-#[unsafe(no_mangle)]
-pub unsafe extern "C" fn malloc(size: usize) -> *mut u8 {
-  static A: Allocator = Allocator;
-  unsafe { A.alloc(Layout::from_size_align_unchecked(size.max(1), 16)) }
-}
+type NothrowNew = unsafe extern "C" fn(usize, *const u8) -> *mut u8;
+#[cfg(feature = "c_api")]
+type NothrowNewAligned = unsafe extern "C" fn(usize, usize, *const u8) -> *mut u8;
 
+/// Sized delete. A block of a small class with the default alignment always has its span header
+/// at the span-aligned address below it, whether it came from a small span or (when the thread
+/// heap was unavailable) a Large run or huge mapping, so the page-map lookup is skipped for it.
+/// The segment map is still checked first: a block from another allocator has no header.
 #[cfg(feature = "c_api")]
-#[unsafe(no_mangle)]
-pub unsafe extern "C" fn free(ptr: *mut u8) {
+unsafe fn cxx_free_sized(ptr: *mut u8, size: usize) {
   if ptr.is_null() {
     return;
   }
-  static A: Allocator = Allocator;
-  unsafe { A.dealloc(ptr, Layout::from_size_align_unchecked(1, 1)) }
+  if size <= CLASSES_MAX_SIZE
+    && let Some(arena) = Arena::owner(ptr)
+  {
+    let span = (ptr as usize & !(SPAN_SIZE - 1)) as *mut SpanHeader;
+    if unsafe { (*span).kind } == SpanKind::Small {
+      return free_small(arena, ptr, span);
+    }
+  }
+  unsafe { free(ptr) }
 }
 
+/// `operator new(size_t, const std::nothrow_t&)`
 #[cfg(feature = "c_api")]
-#[unsafe(no_mangle)]
-pub unsafe extern "C" fn calloc(nmemb: usize, size: usize) -> *mut u8 {
-  let total = nmemb.saturating_mul(size);
-  if total == 0 {
-    return null_mut();
+#[unsafe(export_name = "_ZnwmRKSt9nothrow_t")]
+pub unsafe extern "C" fn cxx_new_nothrow(size: usize, nothrow: *const u8) -> *mut u8 {
+  let ptr = alloc_aligned(CXX_NEW_ALIGN, size);
+  if ptr.is_null() {
+    return cxx_new_nothrow_next(c"_ZnwmRKSt9nothrow_t", size, None, nothrow);
   }
-  static A: Allocator = Allocator;
-  unsafe { A.alloc_zeroed(Layout::from_size_align_unchecked(total, 8)) }
+  ptr
 }
 
+/// `operator new[](size_t, const std::nothrow_t&)`
 #[cfg(feature = "c_api")]
-#[unsafe(no_mangle)]
-pub unsafe extern "C" fn realloc(ptr: *mut u8, size: usize) -> *mut u8 {
-  static A: Allocator = Allocator;
+#[unsafe(export_name = "_ZnamRKSt9nothrow_t")]
+pub unsafe extern "C" fn cxx_new_array_nothrow(size: usize, nothrow: *const u8) -> *mut u8 {
+  let ptr = alloc_aligned(CXX_NEW_ALIGN, size);
+  if ptr.is_null() {
+    return cxx_new_nothrow_next(c"_ZnamRKSt9nothrow_t", size, None, nothrow);
+  }
+  ptr
+}
 
+/// `operator new(size_t, std::align_val_t, const std::nothrow_t&)`
+#[cfg(feature = "c_api")]
+#[unsafe(export_name = "_ZnwmSt11align_val_tRKSt9nothrow_t")]
+pub unsafe extern "C" fn cxx_new_aligned_nothrow(
+  size: usize,
+  align: usize,
+  nothrow: *const u8,
+) -> *mut u8 {
+  let ptr = alloc_aligned(align, size);
   if ptr.is_null() {
-    return unsafe { A.alloc(Layout::from_size_align_unchecked(size.max(1), 8)) };
+    return cxx_new_nothrow_next(
+      c"_ZnwmSt11align_val_tRKSt9nothrow_t",
+      size,
+      Some(align),
+      nothrow,
+    );
   }
+  ptr
+}
 
-  if size == 0 {
-    unsafe { A.dealloc(ptr, Layout::from_size_align_unchecked(1, 1)) };
-    return null_mut();
+/// `operator new[](size_t, std::align_val_t, const std::nothrow_t&)`
+#[cfg(feature = "c_api")]
+#[unsafe(export_name = "_ZnamSt11align_val_tRKSt9nothrow_t")]
+pub unsafe extern "C" fn cxx_new_array_aligned_nothrow(
+  size: usize,
+  align: usize,
+  nothrow: *const u8,
+) -> *mut u8 {
+  let ptr = alloc_aligned(align, size);
+  if ptr.is_null() {
+    return cxx_new_nothrow_next(
+      c"_ZnamSt11align_val_tRKSt9nothrow_t",
+      size,
+      Some(align),
+      nothrow,
+    );
   }
+  ptr
+}
 
-  // C realloc lacks old-size: conservatively copy `size` bytes.
-  let new_ptr = unsafe { A.alloc(Layout::from_size_align_unchecked(size, 8)) };
+/// `operator delete(void*)`
+#[cfg(feature = "c_api")]
+#[unsafe(export_name = "_ZdlPv")]
+pub unsafe extern "C" fn cxx_delete(ptr: *mut u8) {
+  unsafe { free(ptr) }
+}
 
-  if !new_ptr.is_null() {
-    unsafe { ptr::copy_nonoverlapping(ptr, new_ptr, size) };
-    unsafe { A.dealloc(ptr, Layout::from_size_align_unchecked(1, 1)) };
-  }
+/// `operator delete[](void*)`
+#[cfg(feature = "c_api")]
+#[unsafe(export_name = "_ZdaPv")]
+pub unsafe extern "C" fn cxx_delete_array(ptr: *mut u8) {
+  unsafe { free(ptr) }
+}
 
-  new_ptr
+/// `operator delete(void*, size_t)`
+#[cfg(feature = "c_api")]
+#[unsafe(export_name = "_ZdlPvm")]
+pub unsafe extern "C" fn cxx_delete_sized(ptr: *mut u8, size: usize) {
+  unsafe { cxx_free_sized(ptr, size) }
 }
 
+/// `operator delete[](void*, size_t)`
 #[cfg(feature = "c_api")]
-#[unsafe(no_mangle)]
-pub unsafe extern "C" fn posix_memalign(
-  memptr: *mut *mut u8,
-  alignment: usize,
-  size: usize,
-) -> i32 {
-  if !alignment.is_power_of_two() || alignment < core::mem::size_of::<*mut u8>() {
-    return 22; // EINVAL
-  }
+#[unsafe(export_name = "_ZdaPvm")]
+pub unsafe extern "C" fn cxx_delete_array_sized(ptr: *mut u8, size: usize) {
+  unsafe { cxx_free_sized(ptr, size) }
+}
 
-  static A: Allocator = Allocator;
-  let ptr = unsafe { A.alloc(Layout::from_size_align_unchecked(size.max(1), alignment)) };
+/// `operator delete(void*, const std::nothrow_t&)`
+#[cfg(feature = "c_api")]
+#[unsafe(export_name = "_ZdlPvRKSt9nothrow_t")]
+pub unsafe extern "C" fn cxx_delete_nothrow(ptr: *mut u8, _nothrow: *const u8) {
+  unsafe { free(ptr) }
+}
 
-  if ptr.is_null() {
-    return 12; // ENOMEM
-  }
+/// `operator delete[](void*, const std::nothrow_t&)`
+#[cfg(feature = "c_api")]
+#[unsafe(export_name = "_ZdaPvRKSt9nothrow_t")]
+pub unsafe extern "C" fn cxx_delete_array_nothrow(ptr: *mut u8, _nothrow: *const u8) {
+  unsafe { free(ptr) }
+}
 
-  unsafe { *memptr = ptr };
-  0
+/// `operator delete(void*, std::align_val_t)`
+#[cfg(feature = "c_api")]
+#[unsafe(export_name = "_ZdlPvSt11align_val_t")]
+pub unsafe extern "C" fn cxx_delete_aligned(ptr: *mut u8, _align: usize) {
+  unsafe { free(ptr) }
 }
 
+/// `operator delete[](void*, std::align_val_t)`
 #[cfg(feature = "c_api")]
-#[unsafe(no_mangle)]
-pub unsafe extern "C" fn malloc_usable_size(ptr: *mut u8) -> usize {
-  if ptr.is_null() {
-    return 0;
-  }
+#[unsafe(export_name = "_ZdaPvSt11align_val_t")]
+pub unsafe extern "C" fn cxx_delete_array_aligned(ptr: *mut u8, _align: usize) {
+  unsafe { free(ptr) }
+}
 
-  if let Some(arena) = ARENA.get() {
-    if arena.contains(ptr) {
-      let span = arena.ptr_to_span(ptr);
-      return match unsafe { (*span).kind } {
-        SpanKind::Small => unsafe { (*span).block_size as usize },
-        SpanKind::Large => {
-          let order = unsafe { (*span).order as usize };
-          (SPAN_SIZE << order) - SPAN_HEADER_SIZE
-        }
-        SpanKind::Huge => unsafe { (*span).huge_size.saturating_sub(SPAN_HEADER_SIZE + 64) },
-      };
-    }
-  }
+/// `operator delete(void*, size_t, std::align_val_t)`. Over-aligned blocks may sit anywhere in
+/// a medium span or past the first span of a Large run, so this takes the general path.
+#[cfg(feature = "c_api")]
+#[unsafe(export_name = "_ZdlPvmSt11align_val_t")]
+pub unsafe extern "C" fn cxx_delete_sized_aligned(ptr: *mut u8, _size: usize, _align: usize) {
+  unsafe { free(ptr) }
+}
 
-  // Foreign pointer: conservative fallback
-  0
+/// `operator delete[](void*, size_t, std::align_val_t)`
+#[cfg(feature = "c_api")]
+#[unsafe(export_name = "_ZdaPvmSt11align_val_t")]
+pub unsafe extern "C" fn cxx_delete_array_sized_aligned(ptr: *mut u8, _size: usize, _align: usize) {
+  unsafe { free(ptr) }
 }
 
-pub unsafe fn ralloc_malloc(size: usize) -> *mut u8 {
-  static A: Allocator = Allocator;
-  unsafe { A.alloc(Layout::from_size_align_unchecked(size.max(1), 8)) }
+/// `operator delete(void*, std::align_val_t, const std::nothrow_t&)`
+#[cfg(feature = "c_api")]
+#[unsafe(export_name = "_ZdlPvSt11align_val_tRKSt9nothrow_t")]
+pub unsafe extern "C" fn cxx_delete_aligned_nothrow(
+  ptr: *mut u8,
+  _align: usize,
+  _nothrow: *const u8,
+) {
+  unsafe { free(ptr) }
 }
 
-pub unsafe fn ralloc_free(ptr: *mut u8) {
-  static A: Allocator = Allocator;
-  unsafe { A.dealloc(ptr, Layout::from_size_align_unchecked(1, 1)) }
+/// `operator delete[](void*, std::align_val_t, const std::nothrow_t&)`
+#[cfg(feature = "c_api")]
+#[unsafe(export_name = "_ZdaPvSt11align_val_tRKSt9nothrow_t")]
+pub unsafe extern "C" fn cxx_delete_array_aligned_nothrow(
+  ptr: *mut u8,
+  _align: usize,
+  _nothrow: *const u8,
+) {
+  unsafe { free(ptr) }
 }
 
 // =============================================================================
@@ -1453,6 +4668,44 @@ const fn align_up(x: usize, align: usize) -> usize {
   (x + mask) & !mask
 }
 
+/// Parses the leading decimal digits of `digits` (0 if none).
+fn parse_u64(digits: &[u8]) -> u64 {
+  digits
+    .iter()
+    .take_while(|b| b.is_ascii_digit())
+    .fold(0u64, |acc, &b| {
+      acc.wrapping_mul(10).wrapping_add((b - b'0') as u64)
+    })
+}
+
+/// Parses the leading hex digits of `digits` (0 if none).
+fn parse_hex(digits: &[u8]) -> usize {
+  digits
+    .iter()
+    .map_while(|&b| (b as char).to_digit(16))
+    .fold(0usize, |acc, d| {
+      acc.wrapping_mul(16).wrapping_add(d as usize)
+    })
+}
+
+/// Writes `value` in decimal at the start of `buf`, returning the length written.
+fn write_decimal(buf: &mut [u8], mut value: usize) -> usize {
+  let mut digits = [0u8; 20];
+  let mut n = 0;
+  loop {
+    digits[n] = b'0' + (value % 10) as u8;
+    n += 1;
+    value /= 10;
+    if value == 0 {
+      break;
+    }
+  }
+  for (dst, &digit) in buf.iter_mut().zip(digits[..n].iter().rev()) {
+    *dst = digit;
+  }
+  n
+}
+
 // =============================================================================
 // Size Classes
 // =============================================================================
@@ -1461,9 +4714,9 @@ const fn align_up(x: usize, align: usize) -> usize {
 /// Each represents a fraction of the doubling: 1.0, 1.19, 1.44, 1.69
 const GEO_MULTIPLIERS: [usize; 4] = [16, 19, 23, 27];
 
-/// Convert class index to allocation size (inverse of `size_to_class`).
-#[inline(always)]
-const fn class_to_size(class: usize) -> usize {
+/// Size of a class on the linear/geometric progression, before the top class of each tier is
+/// trimmed to fit its spans.
+const fn class_raw_size(class: usize) -> usize {
   if class < CLASSES_LINEAR {
     (class + 1) * CLASSES_LINEAR_STEP
   } else {
@@ -1472,14 +4725,33 @@ const fn class_to_size(class: usize) -> usize {
     let sub = geo_index % CLASSES_PER_DOUBLING;
 
     let base = 128 << order;
-    let size = align_up((base * GEO_MULTIPLIERS[sub]) / 16, 16);
+    align_up((base * GEO_MULTIPLIERS[sub]) / 16, 16)
+  }
+}
 
-    if size > CLASSES_MAX_SIZE {
-      CLASSES_MAX_SIZE
-    } else {
-      size
-    }
+/// Convert class index to allocation size (inverse of `size_to_class`).
+#[inline(always)]
+const fn class_to_size(class: usize) -> usize {
+  if class == SMALL_CLASSES_COUNT - 1 {
+    CLASSES_MAX_SIZE
+  } else if class >= CLASSES_COUNT - 1 {
+    MEDIUM_MAX_SIZE
+  } else {
+    class_raw_size(class)
+  }
+}
+
+/// Buddy order of the spans serving `class`: 0 for small classes, otherwise the smallest block
+/// holding `MEDIUM_MIN_BLOCKS` blocks.
+const fn class_order(class: usize) -> usize {
+  let size = class_to_size(class);
+  let mut order = 0;
+  while size > CLASSES_MAX_SIZE
+    && ((SPAN_SIZE << order) - SPAN_HEADER_SIZE) / size < MEDIUM_MIN_BLOCKS
+  {
+    order += 1;
   }
+  order
 }
 
 /// Convert allocation size to class index (inverse of `class_to_size`).
@@ -1488,9 +4760,14 @@ fn size_to_class(size: usize) -> usize {
   if size == 0 {
     return 0;
   }
-  if size > CLASSES_MAX_SIZE {
+  if size > MEDIUM_MAX_SIZE {
     return CLASSES_COUNT - 1;
   }
+  // The top small class is trimmed to fit two blocks per span: the rest of its geometric range
+  // goes to the first medium class.
+  if size > CLASSES_MAX_SIZE && size <= SPAN_SIZE / 2 {
+    return SMALL_CLASSES_COUNT;
+  }
 
   // Linear range: ceil(size / 16) - 1, clamped
   if size <= 128 {
@@ -1520,3 +4797,193 @@ fn size_to_class(size: usize) -> usize {
   let geo_index = final_order * CLASSES_PER_DOUBLING + sub;
   CLASSES_LINEAR + geo_index - 1
 }
+
+/// Smallest class of at least `size` bytes whose blocks are all `align`-aligned (see
+/// `init_span`), if any.
+fn size_to_class_aligned(size: usize, align: usize) -> Option<usize> {
+  if size.max(align) > MEDIUM_MAX_SIZE {
+    return None;
+  }
+  (size_to_class(size.max(align))..CLASSES_COUNT)
+    .find(|&class| class_to_size(class).is_multiple_of(align))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::Mutex;
+
+  /// Serializes the tests that touch arenas or option globals.
+  static GLOBALS: Mutex<()> = Mutex::new(());
+
+  #[test]
+  fn parse_size_suffixes() {
+    assert_eq!(parse_size(b"0"), Some(0));
+    assert_eq!(parse_size(b"4096"), Some(4096));
+    assert_eq!(parse_size(b"64k"), Some(64 << 10));
+    assert_eq!(parse_size(b"64K"), Some(64 << 10));
+    assert_eq!(parse_size(b"3m"), Some(3 << 20));
+    assert_eq!(parse_size(b"2G"), Some(2 << 30));
+    assert_eq!(parse_size(b"1t"), Some(1 << 40));
+  }
+
+  #[test]
+  fn parse_size_rejects_overflow_and_garbage() {
+    assert_eq!(parse_size(b"16777215t"), Some(16777215 << 40));
+    assert_eq!(parse_size(b"16777216t"), None);
+    assert_eq!(parse_size(b"17179869184g"), None);
+    assert_eq!(parse_size(b"99999999999999999999"), None);
+    for garbage in ["", "k", "-1", " 1", "1 k", "1kb", "1.5g", "0x10", "12e"] {
+      assert_eq!(parse_size(garbage.as_bytes()), None, "{garbage:?}");
+    }
+  }
+
+  #[test]
+  fn parse_options_applies_valid_keys_only() {
+    let _globals = GLOBALS.lock().unwrap();
+    let saved = (
+      SEGMENT_LIMIT.load(Ordering::Relaxed),
+      TCACHE_SIZE.load(Ordering::Relaxed),
+      HUGE_CACHE_MAX.load(Ordering::Relaxed),
+      REUSE_LIMIT.load(Ordering::Relaxed),
+    );
+
+    let config = parse_options(
+      b" shards = 5 ,tcache=3,bogus=1,huge_cache=64m,reuse_limit=lots,noequals,\
+        arena_size=1500m,numa_nodes=0,,",
+    );
+    assert_eq!(config.shards, 8);
+    assert!(!config.fake_numa);
+    assert_eq!(SEGMENT_LIMIT.load(Ordering::Relaxed), 2);
+    assert_eq!(TCACHE_SIZE.load(Ordering::Relaxed), 3);
+    assert_eq!(HUGE_CACHE_MAX.load(Ordering::Relaxed), 64 << 20);
+    assert_eq!(REUSE_LIMIT.load(Ordering::Relaxed), saved.3);
+
+    // Out-of-range values are clamped, zero counts ignored.
+    let config = parse_options(b"shards=100000,arena_size=1t");
+    assert_eq!(config.shards, MAX_SHARD_COUNT);
+    assert_eq!(SEGMENT_LIMIT.load(Ordering::Relaxed), MAX_SEGMENTS);
+    assert_eq!(parse_options(b"shards=0").shards, SHARD_COUNT);
+
+    SEGMENT_LIMIT.store(saved.0, Ordering::Relaxed);
+    TCACHE_SIZE.store(saved.1, Ordering::Relaxed);
+    HUGE_CACHE_MAX.store(saved.2, Ordering::Relaxed);
+  }
+
+  #[test]
+  fn fake_numa_topology_gives_each_node_its_arena() {
+    let _globals = GLOBALS.lock().unwrap();
+    let config = parse_options(b"numa_nodes=3");
+    assert_eq!((config.nodes, config.fake_numa), (3, true));
+    for (cpu, &node) in config.cpu_node.iter().enumerate() {
+      assert_eq!(node as usize, cpu % 3);
+    }
+
+    // CPUs of a node share its arena, which carves spans from segments of its own.
+    for cpu in 0..6 {
+      let node = config.cpu_node[cpu] as usize;
+      let arena = Arena::for_node(node).unwrap();
+      assert_eq!(arena.node, node);
+      let idx = arena.buddy_alloc(0).unwrap();
+      assert_eq!(arena.segment(idx).node.load(Ordering::Relaxed), node);
+      let owner = Arena::owner(arena.idx_to_span(idx).cast()).unwrap();
+      assert!(ptr::eq(owner, arena));
+      arena.buddy_free(idx, 0, false);
+    }
+
+    assert_eq!(parse_options(b"numa_nodes=1000").nodes, MAX_NUMA_NODES);
+  }
+
+  #[test]
+  fn span_caches_bound_medium_classes_by_bytes() {
+    let _globals = GLOBALS.lock().unwrap();
+    let arena = Arena::for_node(0).unwrap();
+    let spans: Vec<_> = (0..5).map(|_| arena.buddy_alloc(0).unwrap()).collect();
+    let medium = (0..CLASSES_COUNT).find(|&c| class_order(c) > 0).unwrap();
+
+    // Medium spans are 512KB and up: one of them already outweighs four small spans.
+    for (class, limit) in [(0, GLOBAL_CACHE_LIMIT), (medium, 1), (CLASSES_COUNT - 1, 1)] {
+      let cache = GlobalCache::new(1);
+      let pushed = spans
+        .iter()
+        .take_while(|&&idx| cache.push(0, class, arena.idx_to_span(idx)))
+        .count();
+      assert_eq!(pushed, limit, "class {class}");
+    }
+
+    let tcache = TCACHE_SIZE.load(Ordering::Relaxed);
+    let mut heap = ThreadHeap::new();
+    for (class, limit) in [(0, tcache), (medium, 1), (CLASSES_COUNT - 1, 1)] {
+      let pushed = spans
+        .iter()
+        .take_while(|&&idx| heap.cache_push(class, arena.idx_to_span(idx)))
+        .count();
+      assert_eq!(pushed, limit.min(spans.len()), "class {class}");
+      (heap.cache[class], heap.cache_len[class]) = (null_mut(), 0);
+    }
+    drop(heap);
+
+    for idx in spans {
+      arena.buddy_free(idx, 0, false);
+    }
+  }
+
+  #[test]
+  fn huge_blocks_register_only_their_head() {
+    // Mapped with MAP_NORESERVE: only the page map could make a terabyte cost memory.
+    for (size, align) in [(1 << 40, 16), (1 << 30, 1 << 20)] {
+      let ptr = alloc_huge(size, align);
+      assert!(!ptr.is_null());
+      let span = block_span(ptr).unwrap();
+      assert_eq!(span as usize + payload_offset(span), ptr as usize);
+      let tail = page_map_entry(ptr as usize + size / 2, false);
+      assert!(tail.is_none_or(|entry| entry.load(Ordering::Relaxed).is_null()));
+
+      // Growth past the end keeps the block reachable, wherever it lands.
+      let ptr = if align <= SPAN_SIZE {
+        realloc_huge(span, 2 * size)
+      } else {
+        ptr
+      };
+      let span = block_span(ptr).unwrap();
+      free_huge(span);
+      assert!(block_span(ptr).is_none());
+    }
+  }
+
+  #[test]
+  fn coalesce_keeps_commit_accounting() {
+    let _globals = GLOBALS.lock().unwrap();
+    let arena = Arena::for_node(0).unwrap();
+    let idx = arena.buddy_alloc(2).unwrap();
+    let buddy = &arena.segment(idx).buddy;
+
+    // Free the lower half as two order-0 buddies that missed each other, as racing frees can
+    // leave them. Only the higher one has a committed payload.
+    page_map_set(arena.idx_to_span(idx) as usize, 2 * SPAN_SIZE, null_mut());
+    GLOBAL_ACTIVE_SPAN_COUNTER.fetch_sub(2, Ordering::Relaxed);
+    for (span, committed) in [(idx, false), (idx + 1, true)] {
+      buddy.orders[0].lock.lock();
+      unsafe {
+        (*arena.idx_to_span(span)).committed = committed;
+        buddy.push_locked(arena, span, 0);
+      }
+      buddy.orders[0].lock.unlock();
+    }
+    buddy.free_spans.fetch_add(2, Ordering::Relaxed);
+
+    let (free, committed) = buddy.free_counts();
+    assert!(buddy.coalesce(arena) >= 1);
+    let (free_after, committed_after) = buddy.free_counts();
+
+    // The pair left order 0 with its committed block; the merged block stops at order 1, its
+    // buddy still being in use, and keeps the higher half's payload.
+    assert_eq!(free_after[0], free[0] - 2);
+    assert_eq!(committed_after[0], committed[0] - 1);
+    assert_eq!(free_after[1], free[1] + 1);
+    assert_eq!(committed_after[1], committed[1] + 1);
+    assert!(unsafe { (*arena.idx_to_span(idx)).committed });
+
+    arena.buddy_free(idx + 2, 1, false);
+  }
+}
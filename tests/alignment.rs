@@ -0,0 +1,132 @@
+use inictus::Allocator;
+use std::alloc::{GlobalAlloc, Layout};
+
+static A: Allocator = Allocator;
+
+/// One size per tier: small, medium, Large, and huge (above half a segment).
+const SIZES: [usize; 9] = [
+  1,
+  24,
+  100,
+  4000,
+  40_000,
+  300_000,
+  1_500_000,
+  5_000_000,
+  600 << 20,
+];
+
+const MAX_ALIGN_SHIFT: u32 = 30;
+
+/// Touch the first and last byte, so a payload overlapping a header or running past its mapping
+/// shows up.
+unsafe fn fill(ptr: *mut u8, size: usize, tag: u8) {
+  unsafe {
+    *ptr = tag;
+    *ptr.add(size - 1) = tag;
+  }
+}
+
+unsafe fn check(ptr: *mut u8, size: usize, tag: u8) {
+  unsafe {
+    assert_eq!(*ptr, tag);
+    assert_eq!(*ptr.add(size - 1), tag);
+  }
+}
+
+#[test]
+fn alloc_honors_every_alignment() {
+  for shift in 0..=MAX_ALIGN_SHIFT {
+    let align = 1 << shift;
+    for size in SIZES {
+      let layout = Layout::from_size_align(size, align).unwrap();
+      let ptrs: Vec<_> = (0..3).map(|_| unsafe { A.alloc(layout) }).collect();
+      for (i, &ptr) in ptrs.iter().enumerate() {
+        assert!(!ptr.is_null(), "size {size} align {align}");
+        assert_eq!(ptr as usize % align, 0, "size {size} align {align}");
+        unsafe { fill(ptr, size, i as u8) };
+      }
+      for (i, &ptr) in ptrs.iter().enumerate() {
+        unsafe { check(ptr, size, i as u8) };
+        unsafe { A.dealloc(ptr, layout) };
+      }
+    }
+  }
+}
+
+#[test]
+fn alloc_zeroed_honors_alignment() {
+  for shift in 0..=MAX_ALIGN_SHIFT {
+    let align = 1 << shift;
+    for size in SIZES {
+      let layout = Layout::from_size_align(size, align).unwrap();
+      let ptr = unsafe { A.alloc_zeroed(layout) };
+      assert_eq!(ptr as usize % align, 0, "size {size} align {align}");
+      unsafe {
+        assert_eq!((*ptr, *ptr.add(size - 1)), (0, 0));
+        A.dealloc(ptr, layout);
+      }
+    }
+  }
+}
+
+#[test]
+fn realloc_keeps_alignment() {
+  for shift in 0..=MAX_ALIGN_SHIFT {
+    let align = 1 << shift;
+    let mut size = SIZES[0];
+    let mut ptr = unsafe { A.alloc(Layout::from_size_align(size, align).unwrap()) };
+    unsafe { *ptr = 0xa5 };
+
+    // Grow through every tier, then shrink back down.
+    for new_size in SIZES
+      .into_iter()
+      .skip(1)
+      .chain(SIZES.into_iter().rev().skip(1))
+    {
+      let layout = Layout::from_size_align(size, align).unwrap();
+      ptr = unsafe { A.realloc(ptr, layout, new_size) };
+      assert!(!ptr.is_null(), "{size} -> {new_size} align {align}");
+      assert_eq!(
+        ptr as usize % align,
+        0,
+        "{size} -> {new_size} align {align}"
+      );
+      assert_eq!(unsafe { *ptr }, 0xa5, "{size} -> {new_size} align {align}");
+      unsafe { *ptr.add(new_size - 1) = 0x5a };
+      size = new_size;
+    }
+
+    unsafe { A.dealloc(ptr, Layout::from_size_align(size, align).unwrap()) };
+  }
+}
+
+#[cfg(feature = "c_api")]
+#[test]
+fn posix_memalign_honors_every_alignment() {
+  for shift in 3..=MAX_ALIGN_SHIFT {
+    let align = 1 << shift;
+    for size in SIZES {
+      let mut ptr = std::ptr::null_mut();
+      assert_eq!(unsafe { inictus::posix_memalign(&mut ptr, align, size) }, 0);
+      assert_eq!(ptr as usize % align, 0, "size {size} align {align}");
+      unsafe {
+        fill(ptr, size, 7);
+        assert!(inictus::malloc_usable_size(ptr) >= size);
+        inictus::free(ptr);
+      }
+    }
+  }
+}
+
+#[cfg(feature = "c_api")]
+#[test]
+fn posix_memalign_rejects_bad_alignment() {
+  let mut ptr = std::ptr::null_mut();
+  for align in [0, 1, 2, 4, 24, 100, 4097] {
+    assert_eq!(
+      unsafe { inictus::posix_memalign(&mut ptr, align, 64) },
+      libc::EINVAL
+    );
+  }
+}
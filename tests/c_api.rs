@@ -0,0 +1,265 @@
+#![cfg(feature = "c_api")]
+
+use inictus::*;
+use std::ptr::null_mut;
+
+const PAGE: usize = 4096;
+const SPAN: usize = 64 << 10;
+
+fn errno() -> i32 {
+  std::io::Error::last_os_error().raw_os_error().unwrap()
+}
+
+#[test]
+fn aligned_alloc_requires_power_of_two() {
+  for align in [0, 3, 24, 100, 4097] {
+    assert!(unsafe { aligned_alloc(align, 64) }.is_null());
+    assert_eq!(errno(), libc::EINVAL);
+  }
+  for align in [1, 8, 64, PAGE, 1 << 20] {
+    let ptr = unsafe { aligned_alloc(align, 100) };
+    assert_eq!(ptr as usize % align, 0);
+    unsafe { free_aligned_sized(ptr, align, 100) };
+  }
+}
+
+#[test]
+fn memalign_rounds_alignment_up() {
+  for (align, expect) in [(0, 1), (3, 4), (24, 32), (100, 128), (4097, 8192)] {
+    let ptr = unsafe { memalign(align, 1000) };
+    assert_eq!(ptr as usize % expect, 0, "align {align}");
+    unsafe { free(ptr) };
+  }
+  assert!(unsafe { memalign(usize::MAX, 64) }.is_null());
+  assert_eq!(errno(), libc::EINVAL);
+}
+
+#[test]
+fn valloc_and_pvalloc_are_page_aligned() {
+  for size in [0, 1, PAGE, PAGE + 1, 100_000, 2_000_000] {
+    let v = unsafe { valloc(size) };
+    let p = unsafe { pvalloc(size) };
+    assert_eq!(v as usize % PAGE, 0);
+    assert_eq!(p as usize % PAGE, 0);
+    unsafe {
+      assert!(malloc_usable_size(v) >= size);
+      assert!(malloc_usable_size(p) >= size.max(1).next_multiple_of(PAGE));
+      p.add(size.max(1).next_multiple_of(PAGE) - 1).write(1);
+      free(v);
+      free(p);
+    }
+  }
+  assert!(unsafe { pvalloc(usize::MAX) }.is_null());
+  assert_eq!(errno(), libc::ENOMEM);
+}
+
+#[test]
+fn oversized_requests_set_enomem() {
+  assert!(unsafe { malloc(usize::MAX) }.is_null());
+  assert_eq!(errno(), libc::ENOMEM);
+  assert!(unsafe { aligned_alloc(64, usize::MAX - 8) }.is_null());
+  assert_eq!(errno(), libc::ENOMEM);
+  let mut ptr = null_mut();
+  assert_eq!(
+    unsafe { posix_memalign(&mut ptr, 64, usize::MAX) },
+    libc::ENOMEM
+  );
+}
+
+#[test]
+fn reallocarray_checks_overflow() {
+  let ptr = unsafe { reallocarray(null_mut(), 10, 8) };
+  assert!(!ptr.is_null());
+  unsafe { ptr.write_bytes(7, 80) };
+
+  assert!(unsafe { reallocarray(ptr, usize::MAX / 2, 3) }.is_null());
+  assert_eq!(errno(), libc::ENOMEM);
+  // The original block is untouched.
+  assert_eq!(unsafe { *ptr.add(79) }, 7);
+
+  let ptr = unsafe { reallocarray(ptr, 1000, 8) };
+  assert_eq!(unsafe { *ptr.add(79) }, 7);
+  unsafe { free_sized(ptr, 8000) };
+}
+
+#[test]
+fn cxx_new_and_sized_delete() {
+  for size in [0, 1, 24, 4000, 32_000, 40_000, 300_000, 3_000_000] {
+    let ptr = unsafe { cxx_new(size) };
+    let array = unsafe { cxx_new_array(size) };
+    assert_eq!((ptr as usize % 16, array as usize % 16), (0, 0));
+    unsafe {
+      ptr.write_bytes(1, size);
+      array.write_bytes(2, size);
+      cxx_delete_sized(ptr, size);
+      cxx_delete_array_sized(array, size);
+    }
+  }
+  unsafe { cxx_delete_sized(null_mut(), 8) };
+}
+
+#[test]
+fn cxx_aligned_new() {
+  for shift in 4..=20 {
+    let align = 1 << shift;
+    for size in [1, 100, 40_000, 3_000_000] {
+      let ptr = unsafe { cxx_new_aligned(size, align) };
+      let nothrow = unsafe { cxx_new_aligned_nothrow(size, align, null_mut()) };
+      assert_eq!((ptr as usize % align, nothrow as usize % align), (0, 0));
+      unsafe {
+        cxx_delete_sized_aligned(ptr, size, align);
+        cxx_delete_aligned_nothrow(nothrow, align, null_mut());
+      }
+    }
+  }
+}
+
+#[test]
+fn mallinfo2_reports_huge_mappings() {
+  let size = 600 << 20;
+  let ptr = unsafe { __libc_malloc(size) };
+  let info = mallinfo2();
+  assert!(info.hblks >= 1);
+  // The mapping covers the block, rounded up to its huge class.
+  let usable = unsafe { malloc_usable_size(ptr) };
+  assert!(usable >= size);
+  assert!(info.hblkhd >= usable);
+  unsafe { __libc_free(ptr) };
+}
+
+#[test]
+fn mallopt_maps_glibc_parameters() {
+  const M_MXFAST: i32 = 1;
+  const M_TRIM_THRESHOLD: i32 = -1;
+  const M_MMAP_THRESHOLD: i32 = -3;
+  const M_ARENA_MAX: i32 = -8;
+
+  // Above the new threshold, a block gets a mapping rounded up to its huge class (448MB)
+  // rather than a Large run, which would end within a span of it. No other test here
+  // allocates between 300MB and 512MB.
+  assert_eq!(mallopt(M_MMAP_THRESHOLD, 300 << 20), 1);
+  let size = 400 << 20;
+  let ptr = unsafe { malloc(size) };
+  assert!(unsafe { malloc_usable_size(ptr) } > size + SPAN);
+  unsafe { free(ptr) };
+  assert_eq!(mallopt(M_MMAP_THRESHOLD, i32::MAX), 1);
+
+  // Arenas follow the NUMA topology and cannot be capped.
+  assert_eq!(mallopt(M_ARENA_MAX, 4), 0);
+
+  assert_eq!(mallopt(M_TRIM_THRESHOLD, -1), 0);
+  assert_eq!(mallopt(M_MXFAST, 64), 0);
+  assert_eq!(mallopt(42, 0), 0);
+}
+
+/// Read back what `f` writes to the temporary file it is given.
+fn capture(f: impl FnOnce(*mut libc::FILE)) -> String {
+  unsafe {
+    let file = libc::tmpfile();
+    f(file);
+    libc::fflush(file);
+    let mut out = vec![0u8; 1 << 16];
+    let len = libc::pread(libc::fileno(file), out.as_mut_ptr().cast(), out.len(), 0);
+    libc::fclose(file);
+    out.truncate(len as usize);
+    String::from_utf8(out).unwrap()
+  }
+}
+
+#[test]
+fn malloc_stats_prints_to_stderr() {
+  let out = capture(|file| unsafe {
+    let stderr = libc::dup(2);
+    libc::dup2(libc::fileno(file), 2);
+    malloc_stats();
+    libc::dup2(stderr, 2);
+    libc::close(stderr);
+  });
+  assert!(out.contains("system bytes"), "{out}");
+  assert!(out.contains("buddy free"), "{out}");
+}
+
+#[test]
+fn malloc_info_writes_xml() {
+  let ptr = unsafe { malloc(100) };
+  let out = capture(|file| assert_eq!(unsafe { malloc_info(0, file) }, 0));
+  unsafe { free(ptr) };
+  assert!(out.starts_with("<malloc version=\"1\">"), "{out}");
+  assert!(out.contains("<total type=\"mmap\""), "{out}");
+  assert!(out.trim_end().ends_with("</malloc>"), "{out}");
+
+  assert_eq!(unsafe { malloc_info(1, null_mut()) }, -1);
+  assert_eq!(errno(), libc::EINVAL);
+}
+
+#[test]
+fn realloc_copies_only_the_old_block() {
+  unsafe {
+    let ptr = malloc(1000);
+    ptr.write_bytes(3, 16);
+    // Anything that still fits: same pointer.
+    assert_eq!(realloc(ptr, 10), ptr);
+    assert_eq!(realloc(ptr, 1000), ptr);
+
+    let grown = realloc(ptr, 1 << 20);
+    assert_eq!(*grown.add(15), 3);
+    grown.write_bytes(4, 1 << 20);
+
+    let huge = realloc(grown, 900 << 20);
+    assert_eq!(*huge.add((1 << 20) - 1), 4);
+    // Huge blocks shrink in place, giving their tail pages back.
+    let before = malloc_usable_size(huge);
+    assert_eq!(realloc(huge, 700 << 20), huge);
+    assert!(malloc_usable_size(huge) < before);
+
+    // Below the huge tier the block moves, and the mapping goes away.
+    let small = realloc(huge, 100);
+    assert_ne!(small, huge);
+    assert!(malloc_usable_size(small) < 1 << 10);
+    assert_eq!(*small.add(99), 4);
+    free(small);
+
+    // Large runs drop their tail spans, and move below the Large tier.
+    let large = malloc(100 << 20);
+    large.write_bytes(5, 100);
+    assert_eq!(realloc(large, 50 << 20), large);
+    assert!(malloc_usable_size(large) < (50 << 20) + SPAN);
+    let small = realloc(large, 100);
+    assert_ne!(small, large);
+    assert!(malloc_usable_size(small) < 1 << 10);
+    assert_eq!(*small.add(99), 5);
+    free(small);
+  }
+}
+
+#[test]
+fn realloc_does_not_read_past_the_old_block() {
+  // Large runs of exactly 32 spans, carved one after another from the same buddy block.
+  let size = 32 * SPAN - 128;
+  let ptrs: Vec<_> = (0..8).map(|_| unsafe { malloc(size) }).collect();
+  let usable = |ptr| unsafe { malloc_usable_size(ptr) };
+  let (ptr, next) = ptrs
+    .iter()
+    .flat_map(|&a| ptrs.iter().map(move |&b| (a, b)))
+    .find(|&(a, b)| b as usize & !(SPAN - 1) == a as usize + usable(a))
+    .expect("no adjacent runs");
+
+  // Guard the run after `ptr`: copying more than the old block faults.
+  let guard = (next as usize & !(SPAN - 1)) as *mut libc::c_void;
+  let guard_len = next as usize + usable(next) - guard as usize;
+  unsafe {
+    assert_eq!(libc::mprotect(guard, guard_len, libc::PROT_NONE), 0);
+    let old = usable(ptr);
+    ptr.add(old - 1).write(9);
+    let grown = realloc(ptr, 2 * size);
+    assert_eq!(*grown.add(old - 1), 9);
+    assert_eq!(
+      libc::mprotect(guard, guard_len, libc::PROT_READ | libc::PROT_WRITE),
+      0
+    );
+    free(grown);
+    for other in ptrs.into_iter().filter(|&other| other != ptr) {
+      free(other);
+    }
+  }
+}
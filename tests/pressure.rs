@@ -0,0 +1,86 @@
+#![cfg(feature = "pressure")]
+
+use inictus::Allocator;
+use std::alloc::{GlobalAlloc, Layout};
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+static A: Allocator = Allocator;
+
+/// Wait until the watcher has handled more than `seen` events.
+fn wait_for_event(seen: usize) -> bool {
+  let deadline = Instant::now() + Duration::from_secs(5);
+  while Instant::now() < deadline {
+    if inictus::stats().pressure_events > seen {
+      return true;
+    }
+    std::thread::sleep(Duration::from_millis(20));
+  }
+  false
+}
+
+fn scratch(name: &str) -> PathBuf {
+  let path = std::env::temp_dir().join(format!("inictus-{}-{name}", std::process::id()));
+  let _ = fs::remove_file(&path);
+  path
+}
+
+fn psi(total: u64) -> String {
+  format!(
+    "some avg10=0.00 avg60=0.00 avg300=0.00 total={total}\n\
+     full avg10=0.00 avg60=0.00 avg300=0.00 total={total}\n"
+  )
+}
+
+// One test, so the file and FIFO sources never race for the single watcher.
+#[test]
+fn pressure_sources_trigger_purges() {
+  // Plain file in PSI format: stall time growing by 150ms between two reads is pressure.
+  let file = scratch("psi");
+  fs::write(&file, psi(1_000)).unwrap();
+  assert!(inictus::set_pressure_source(file.to_str()));
+  let seen = inictus::stats().pressure_events;
+  // Growth below the threshold is not pressure.
+  fs::write(&file, psi(100_000)).unwrap();
+  std::thread::sleep(Duration::from_millis(1500));
+  assert_eq!(inictus::stats().pressure_events, seen);
+  fs::write(&file, psi(400_000)).unwrap();
+  assert!(wait_for_event(seen), "PSI file growth not seen");
+  assert!(!inictus::set_pressure_source(None));
+  fs::remove_file(&file).unwrap();
+
+  // FIFO: every write is an event, and it purges the shared caches.
+  let fifo = scratch("fifo");
+  let cpath = std::ffi::CString::new(fifo.to_str().unwrap()).unwrap();
+  assert_eq!(unsafe { libc::mkfifo(cpath.as_ptr(), 0o600) }, 0);
+  assert!(inictus::set_pressure_source(fifo.to_str()));
+
+  // Leave fully free spans in the shared caches: a thread's cached spans go there when it exits.
+  std::thread::spawn(|| {
+    let layout = Layout::from_size_align(40_000, 8).unwrap();
+    let ptrs: Vec<_> = (0..256).map(|_| unsafe { A.alloc(layout) }).collect();
+    for ptr in ptrs {
+      unsafe { A.dealloc(ptr, layout) };
+    }
+  })
+  .join()
+  .unwrap();
+  let stats = inictus::stats();
+  assert!(stats.global_cached_spans + stats.empty_cached_spans > 0);
+
+  let seen = inictus::stats().pressure_events;
+  let mut writer = fs::OpenOptions::new().write(true).open(&fifo).unwrap();
+  writer
+    .write_all(b"some avg10=12.00 avg60=3.00 avg300=1.00 total=900000\n")
+    .unwrap();
+  assert!(wait_for_event(seen), "FIFO write not seen");
+  // Stopping joins the watcher, so the purge it started has finished.
+  assert!(!inictus::set_pressure_source(None));
+  let stats = inictus::stats();
+  assert_eq!(stats.global_cached_spans + stats.empty_cached_spans, 0);
+
+  drop(writer);
+  fs::remove_file(&fifo).unwrap();
+}